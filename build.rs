@@ -0,0 +1,18 @@
+use std::fs;
+
+/// Expose version of light-curve-feature locked in Cargo.lock, it is written to run manifests
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let version = lock
+        .split("[[package]]")
+        .find(|package| package.contains("\nname = \"light-curve-feature\"\n"))
+        .and_then(|package| {
+            package
+                .lines()
+                .find_map(|line| line.strip_prefix("version = "))
+                .map(|version| version.trim_matches('"').to_owned())
+        })
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=LIGHT_CURVE_FEATURE_VERSION={}", version);
+}
@@ -0,0 +1,75 @@
+/// CPU numbers from a list like "0-13,28-41"
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = vec![];
+    for range in s.split(',') {
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid CPU number in {}", range))
+        };
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("CPU range {} is reversed", range));
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(parse(range)?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// CPUs the process is allowed to run on, e.g. by docker cpuset
+#[cfg(target_os = "linux")]
+pub fn allowed_cpus() -> Vec<usize> {
+    // Safety: the set is zero-initialized and its size is passed to the call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        let code = libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+        assert_eq!(code, 0, "Cannot get CPU affinity");
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cpus() -> Vec<usize> {
+    panic!("core pinning is supported on Linux only")
+}
+
+/// Restrict the current thread to the CPU
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) {
+    assert!(
+        cpu < libc::CPU_SETSIZE as usize,
+        "CPU number {} is too large",
+        cpu
+    );
+    // Safety: the set is zero-initialized and its size is passed to the call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        let code = libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+        assert_eq!(code, 0, "Cannot pin thread to CPU {}", cpu);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) {
+    panic!("core pinning is supported on Linux only")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_list_from_str() {
+        assert_eq!(parse_cpu_list("0-2,8").unwrap(), [0, 1, 2, 8]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+}
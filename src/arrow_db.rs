@@ -0,0 +1,339 @@
+use crate::config::FileConfig;
+use crate::lc::{Observation, Photometry};
+use crate::traits::{ObservationsToSources, SourceDataBase};
+
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::buffer::ScalarBuffer;
+use arrow::compute::cast;
+use arrow::datatypes::{
+    ArrowPrimitiveType, DataType, Field, Float32Type, Float64Type, UInt64Type, UInt8Type,
+};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::{FileReader, StreamReader};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+/// Number of rows in a record batch read from Parquet file
+const BATCH_SIZE: usize = 1 << 12;
+
+type RecordBatchIterator = Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>;
+
+#[derive(Clone, Copy)]
+pub enum ArrowFormat {
+    Parquet,
+    Ipc,
+}
+
+/// Reads observations from Parquet or Arrow IPC file
+///
+/// Two layouts are supported: flat layout has a row per observation, list layout has a row per
+/// source and passband with list columns of mjd, brightness and its error
+pub struct ArrowSourceDataBase {
+    format: ArrowFormat,
+    config: FileConfig,
+}
+
+impl ArrowSourceDataBase {
+    pub fn new(format: ArrowFormat, config: &FileConfig) -> Self {
+        Self {
+            format,
+            config: config.clone(),
+        }
+    }
+
+    fn parquet_batches(&self, path: &str) -> RecordBatchIterator {
+        assert_ne!(path, "-", "Parquet input cannot be read from stdin");
+        let file = File::open(path).expect("Cannot open input file");
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+            .expect("Cannot read Parquet file metadata");
+        let indices: Vec<_> =
+            self.config
+                .columns
+                .iter()
+                .map(|column| {
+                    builder.schema().index_of(column).unwrap_or_else(|_| {
+                        panic!("Column {} is not found in Parquet file", column)
+                    })
+                })
+                .collect();
+        let mask = ProjectionMask::roots(builder.parquet_schema(), indices);
+        let reader = builder
+            .with_projection(mask)
+            .with_batch_size(BATCH_SIZE)
+            .build()
+            .expect("Cannot build Parquet reader");
+        Box::new(reader)
+    }
+
+    fn ipc_batches(&self, path: &str) -> RecordBatchIterator {
+        if path == "-" {
+            let reader = StreamReader::try_new(BufReader::new(io::stdin()), None)
+                .expect("Cannot read Arrow IPC stream from stdin");
+            return Box::new(reader);
+        }
+        let file = File::open(path).expect("Cannot open input file");
+        match FileReader::try_new(file, None) {
+            Ok(reader) => Box::new(reader),
+            // It is not an IPC file, but it still can be an IPC stream
+            Err(_) => {
+                let file = File::open(path).expect("Cannot open input file");
+                let reader = StreamReader::try_new(BufReader::new(file), None)
+                    .expect("Input is neither Arrow IPC file nor Arrow IPC stream");
+                Box::new(reader)
+            }
+        }
+    }
+}
+
+impl<'a> SourceDataBase<'a> for ArrowSourceDataBase {
+    type Query = ArrowQuery;
+
+    /// `path` is a path to the file, use "-" to read Arrow IPC stream from stdin
+    fn query(&'a mut self, path: &str) -> Self::Query {
+        let batches = match self.format {
+            ArrowFormat::Parquet => self.parquet_batches(path),
+            ArrowFormat::Ipc => self.ipc_batches(path),
+        };
+        ArrowQuery::new(batches, &self.config)
+    }
+}
+
+pub struct ArrowQuery {
+    batches: RecordBatchIterator,
+    columns: [String; 5],
+    photometry: Photometry,
+}
+
+impl ArrowQuery {
+    pub fn new(batches: RecordBatchIterator, config: &FileConfig) -> Self {
+        Self {
+            batches,
+            columns: config.columns.clone(),
+            photometry: config.photometry.clone(),
+        }
+    }
+}
+
+impl IntoIterator for ArrowQuery {
+    type Item = Observation;
+    type IntoIter = ArrowQueryIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Self::IntoIter::new(self)
+    }
+}
+
+struct CurrentBatch {
+    sid: ScalarBuffer<u64>,
+    filter: ScalarBuffer<u8>,
+    mjd: ScalarBuffer<f64>,
+    value: ScalarBuffer<f32>,
+    err: ScalarBuffer<f32>,
+    // Observation index bounds of every row, it is 0..=n_rows for flat layout
+    offsets: Vec<usize>,
+    row: usize,
+    idx: usize,
+}
+
+impl CurrentBatch {
+    fn new(batch: RecordBatch, columns: &[String; 5]) -> Self {
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap_or_else(|| panic!("Column {} is not found in record batch", name))
+        };
+        let [sid_col, mjd_col, filter_col, value_col, err_col] = columns;
+
+        let sid = Self::primitive::<UInt64Type>(column(sid_col));
+        let filter = Self::primitive::<UInt8Type>(column(filter_col));
+        let (offsets, mjd, value, err) = match column(mjd_col).data_type() {
+            DataType::List(_) | DataType::LargeList(_) => {
+                let (offsets, mjd) = Self::list::<Float64Type>(column(mjd_col));
+                let (value_offsets, value) = Self::list::<Float32Type>(column(value_col));
+                let (err_offsets, err) = Self::list::<Float32Type>(column(err_col));
+                assert!(
+                    offsets == value_offsets && offsets == err_offsets,
+                    "{}, {} and {} lists must have the same lengths",
+                    mjd_col,
+                    value_col,
+                    err_col
+                );
+                (offsets, mjd, value, err)
+            }
+            _ => (
+                (0..=batch.num_rows()).collect(),
+                Self::primitive::<Float64Type>(column(mjd_col)),
+                Self::primitive::<Float32Type>(column(value_col)),
+                Self::primitive::<Float32Type>(column(err_col)),
+            ),
+        };
+
+        let idx = offsets[0];
+        Self {
+            sid,
+            filter,
+            mjd,
+            value,
+            err,
+            offsets,
+            row: 0,
+            idx,
+        }
+    }
+
+    fn primitive<T: ArrowPrimitiveType>(array: &ArrayRef) -> ScalarBuffer<T::Native> {
+        let array = cast(array, &T::DATA_TYPE).expect("Cannot cast column to required type");
+        assert_eq!(array.null_count(), 0, "Input columns cannot have nulls");
+        array.as_primitive::<T>().values().clone()
+    }
+
+    fn list<T: ArrowPrimitiveType>(array: &ArrayRef) -> (Vec<usize>, ScalarBuffer<T::Native>) {
+        let item = Arc::new(Field::new("item", T::DATA_TYPE, true));
+        let array = cast(array, &DataType::List(item)).expect("Cannot cast column to list");
+        let list = array.as_list::<i32>();
+        let offsets = list.value_offsets().iter().map(|&x| x as usize).collect();
+        let values = Self::primitive::<T>(list.values());
+        (offsets, values)
+    }
+
+    fn next_obs(&mut self, photometry: &Photometry) -> Option<Observation> {
+        while self.row + 1 < self.offsets.len() && self.idx == self.offsets[self.row + 1] {
+            self.row += 1;
+        }
+        if self.row + 1 == self.offsets.len() {
+            return None;
+        }
+        let obs = photometry.observation(
+            self.sid[self.row],
+            self.filter[self.row],
+            self.mjd[self.idx],
+            self.value[self.idx],
+            self.err[self.idx],
+        );
+        self.idx += 1;
+        Some(obs)
+    }
+}
+
+pub struct ArrowQueryIterator {
+    query: ArrowQuery,
+    batch: Option<CurrentBatch>,
+}
+
+impl ArrowQueryIterator {
+    fn new(query: ArrowQuery) -> Self {
+        Self { query, batch: None }
+    }
+}
+
+impl Iterator for ArrowQueryIterator {
+    type Item = Observation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let photometry = &self.query.photometry;
+            if let Some(obs) = self
+                .batch
+                .as_mut()
+                .and_then(|batch| batch.next_obs(photometry))
+            {
+                return Some(obs);
+            }
+            let batch = self
+                .query
+                .batches
+                .next()?
+                .expect("Cannot read record batch");
+            self.batch = Some(CurrentBatch::new(batch, &self.query.columns));
+        }
+    }
+}
+
+impl ObservationsToSources for ArrowQueryIterator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow::array::{Float32Array, Float64Array, ListArray, UInt32Array, UInt64Array};
+
+    fn observations(batch: RecordBatch) -> Vec<Observation> {
+        let batches = Box::new(vec![Ok(batch)].into_iter());
+        ArrowQuery::new(batches, &FileConfig::default())
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn flat_layout() {
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "sid",
+                Arc::new(UInt64Array::from(vec![1, 1, 2])) as ArrayRef,
+            ),
+            (
+                "mjd",
+                Arc::new(Float64Array::from(vec![58001.5, 58002.0, 58003.0])) as _,
+            ),
+            // filter type differs from u8 and must be casted
+            ("filter", Arc::new(UInt32Array::from(vec![1, 2, 1])) as _),
+            (
+                "mag",
+                Arc::new(Float32Array::from(vec![15.0, 16.0, 17.0])) as _,
+            ),
+            (
+                "magerr",
+                Arc::new(Float32Array::from(vec![0.5, 0.5, 0.25])) as _,
+            ),
+        ])
+        .unwrap();
+        let obs = observations(batch);
+        assert_eq!(obs.len(), 3);
+        assert_eq!(obs[0].t, 1.5);
+        assert_eq!(obs[1].passband, 2);
+        assert_eq!(obs[2].sid, 2);
+        assert_eq!(obs[2].w, 16.0);
+    }
+
+    #[test]
+    fn list_layout() {
+        let mjd = ListArray::from_iter_primitive::<Float64Type, _, _>(vec![
+            Some(vec![Some(58001.5), Some(58002.0)]),
+            Some(vec![]),
+            Some(vec![Some(58003.0)]),
+        ]);
+        let mag = ListArray::from_iter_primitive::<Float32Type, _, _>(vec![
+            Some(vec![Some(15.0), Some(16.0)]),
+            Some(vec![]),
+            Some(vec![Some(17.0)]),
+        ]);
+        let magerr = ListArray::from_iter_primitive::<Float32Type, _, _>(vec![
+            Some(vec![Some(0.5), Some(0.5)]),
+            Some(vec![]),
+            Some(vec![Some(0.25)]),
+        ]);
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "sid",
+                Arc::new(UInt64Array::from(vec![1, 2, 3])) as ArrayRef,
+            ),
+            ("filter", Arc::new(UInt32Array::from(vec![1, 2, 3])) as _),
+            ("mjd", Arc::new(mjd) as _),
+            ("mag", Arc::new(mag) as _),
+            ("magerr", Arc::new(magerr) as _),
+        ])
+        .unwrap();
+        let obs = observations(batch);
+        assert_eq!(obs.len(), 3);
+        assert_eq!(obs[0].sid, 1);
+        assert_eq!(obs[1].sid, 1);
+        assert_eq!(obs[1].t, 2.0);
+        assert_eq!(obs[2].sid, 3);
+        assert_eq!(obs[2].passband, 3);
+        assert_eq!(obs[2].mag, 17.0);
+    }
+}
@@ -1,6 +1,10 @@
-use feat_extr::config::{arg_matches, Config};
-use feat_extr::run;
+use feat_extr::config::{arg_matches, Config, MergeConfig};
+use feat_extr::{merge, run};
 
 fn main() {
-    run(Config::from_arg_matches(&arg_matches()));
+    let matches = arg_matches();
+    match matches.subcommand() {
+        ("merge", Some(merge_matches)) => merge(&MergeConfig::from_arg_matches(merge_matches)),
+        _ => run(Config::from_arg_matches(&matches)),
+    }
 }
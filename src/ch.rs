@@ -1,23 +1,120 @@
-use crate::lc::{Observation, Passband, MJD0};
+use crate::lc::{Observation, Photometry, Source};
+use crate::metrics::Metrics;
 use crate::traits::{ObservationsToSources, SourceDataBase};
 use async_std::task;
 use clickhouse_rs::errors::Error;
 use clickhouse_rs::types::{Block, FromSql};
 use clickhouse_rs::{ClientHandle, Pool};
 use futures_util::stream::{BoxStream, StreamExt};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 pub struct CHSourceDataBase {
     client: ClientHandle,
+    photometry: Photometry,
+    metrics: Arc<Metrics>,
 }
 
 impl CHSourceDataBase {
-    pub fn new(url: &str) -> Self {
+    /// `photometry.brightness` is the type of brightness columns of query responses, they are
+    /// named mag and magerr, or flux and fluxerr. Received blocks are counted by `metrics`
+    pub fn new(url: &str, photometry: Photometry, metrics: Arc<Metrics>) -> Self {
         let pool = Pool::new(url);
         let client = task::block_on(pool.get_handle()).unwrap();
-        Self { client }
+        Self {
+            client,
+            photometry,
+            metrics,
+        }
+    }
+
+    /// Number of distinct sids in the response of the query
+    pub fn count_sources(&mut self, query: &str) -> usize {
+        let query = format!(
+            "SELECT uniqExact(sid) AS count FROM ({})",
+            subquery(query).unwrap()
+        );
+        let block = task::block_on(self.client.query(query).fetch_all()).unwrap();
+        let count: u64 = block.get(0, "count").unwrap();
+        count as usize
     }
 }
 
+/// Query without a trailing semicolon and comments, so it can be used as a subquery
+///
+/// Queries having several statements or FORMAT or SETTINGS clauses cannot be wrapped into
+/// another query, so they are rejected
+pub fn subquery(query: &str) -> Result<&str, String> {
+    let mut chars = query.char_indices().peekable();
+    let mut depth = 0;
+    let mut word = String::new();
+    // End of the statement, trailing semicolon, whitespaces and comments are not included
+    let mut end = 0;
+    let mut terminated = false;
+    loop {
+        let next = chars.next();
+        // Trailing space finishes the last word
+        let c = next.map_or(' ', |(_, c)| c);
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+        } else {
+            if depth == 0
+                && (word.eq_ignore_ascii_case("format") || word.eq_ignore_ascii_case("settings"))
+            {
+                return Err(format!(
+                    "query with {} clause cannot be used as a subquery",
+                    word.to_uppercase()
+                ));
+            }
+            word.clear();
+        }
+        let (i, c) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        match c {
+            '-' if matches!(chars.peek(), Some((_, '-'))) => {
+                chars.find(|&(_, c)| c == '\n');
+                continue;
+            }
+            '/' if matches!(chars.peek(), Some((_, '*'))) => {
+                chars.next();
+                let mut star = false;
+                chars.find(|&(_, c)| {
+                    let closed = star && c == '/';
+                    star = c == '*';
+                    closed
+                });
+                continue;
+            }
+            _ if c.is_whitespace() => continue,
+            _ if terminated => return Err("query must be a single statement".to_owned()),
+            ';' if depth == 0 => {
+                terminated = true;
+                continue;
+            }
+            '\'' | '"' | '`' => {
+                let mut escaped = false;
+                let closing = chars.find(|&(_, q)| {
+                    let closed = !escaped && q == c;
+                    escaped = !escaped && q == '\\';
+                    closed
+                });
+                end = match closing {
+                    Some((j, q)) => j + q.len_utf8(),
+                    None => query.len(),
+                };
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        end = i + c.len_utf8();
+    }
+    Ok(&query[..end])
+}
+
 impl<'a> SourceDataBase<'a> for CHSourceDataBase {
     type Query = CHQuery<'a>;
 
@@ -28,12 +125,30 @@ impl<'a> SourceDataBase<'a> for CHSourceDataBase {
 
 pub struct CHQuery<'a> {
     stream: BoxStream<'a, Result<Block, Error>>,
+    photometry: Photometry,
+    metrics: Arc<Metrics>,
 }
 
 impl<'a> CHQuery<'a> {
     pub fn new(ch_db: &'a mut CHSourceDataBase, query: &str) -> Self {
+        let photometry = ch_db.photometry.clone();
+        let metrics = ch_db.metrics.clone();
         let stream = ch_db.client.query(query).stream_blocks();
-        Self { stream }
+        Self {
+            stream,
+            photometry,
+            metrics,
+        }
+    }
+
+    /// Iterate over sources of the response having a light curve per row
+    ///
+    /// The response must have sid and filter columns, and array columns mjd, mag and magerr, or
+    /// mjd, flux and fluxerr.
+    /// Consecutive rows with the same sid, e.g. light curves in different passbands, are merged
+    /// into a single source
+    pub fn array_rows(self, sorted: bool) -> CHArrayQueryIterator<'a> {
+        CHArrayQueryIterator::new(self, sorted)
     }
 }
 
@@ -77,43 +192,30 @@ impl CurrentBlock {
     }
 }
 
-pub struct CHQueryIterator<'a> {
-    query: CHQuery<'a>,
+struct BlockRows<'a> {
+    stream: BoxStream<'a, Result<Block, Error>>,
     block: Option<CurrentBlock>,
+    metrics: Arc<Metrics>,
 }
 
-impl<'a> CHQueryIterator<'a> {
+impl<'a> BlockRows<'a> {
     fn new(query: CHQuery<'a>) -> Self {
-        Self { query, block: None }
-    }
-
-    fn row_to_obs(row: Row) -> Observation {
-        let sid: u64 = row.get("sid").unwrap();
-        let filter: u8 = row.get("filter").unwrap();
-        let mjd: f64 = row.get("mjd").unwrap();
-        let t = (mjd - MJD0) as f32;
-        let mag: f32 = row.get("mag").unwrap();
-        let magerr: f32 = row.get("magerr").unwrap();
-        let w = magerr.powi(-2);
-        Observation {
-            sid,
-            passband: Passband::from_code(filter),
-            t,
-            mag,
-            w,
+        Self {
+            stream: query.stream,
+            block: None,
+            metrics: query.metrics,
         }
     }
-}
 
-impl<'a> Iterator for CHQueryIterator<'a> {
-    type Item = Observation;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_with<T>(&mut self, f: impl FnOnce(Row) -> T) -> Option<T> {
         while self.block.is_none()
             || self.block.as_ref().unwrap().size == self.block.as_ref().unwrap().idx
         {
-            match task::block_on(self.query.stream.next()) {
-                Some(block) => self.block = Some(CurrentBlock::new(block.unwrap())),
+            match task::block_on(self.stream.next()) {
+                Some(block) => {
+                    self.metrics.db_blocks.fetch_add(1, Ordering::Relaxed);
+                    self.block = Some(CurrentBlock::new(block.unwrap()));
+                }
                 None => return None,
             }
         }
@@ -121,7 +223,7 @@ impl<'a> Iterator for CHQueryIterator<'a> {
         match &mut self.block {
             Some(cur_block) => {
                 cur_block.idx += 1;
-                Some(Self::row_to_obs(Row {
+                Some(f(Row {
                     block: &cur_block.block,
                     idx: cur_block.idx - 1,
                 }))
@@ -131,4 +233,214 @@ impl<'a> Iterator for CHQueryIterator<'a> {
     }
 }
 
+pub struct CHQueryIterator<'a> {
+    photometry: Photometry,
+    rows: BlockRows<'a>,
+}
+
+impl<'a> CHQueryIterator<'a> {
+    fn new(query: CHQuery<'a>) -> Self {
+        Self {
+            photometry: query.photometry.clone(),
+            rows: BlockRows::new(query),
+        }
+    }
+
+    fn row_to_obs(row: Row, photometry: &Photometry) -> Observation {
+        let [value_col, err_col] = photometry.brightness.columns();
+        let sid: u64 = row.get("sid").unwrap();
+        let filter: u8 = row.get("filter").unwrap();
+        let mjd: f64 = row.get("mjd").unwrap();
+        let value: f32 = row.get(value_col).unwrap();
+        let err: f32 = row.get(err_col).unwrap();
+        photometry.observation(sid, filter, mjd, value, err)
+    }
+}
+
+impl<'a> Iterator for CHQueryIterator<'a> {
+    type Item = Observation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let photometry = &self.photometry;
+        self.rows
+            .next_with(|row: Row| Self::row_to_obs(row, photometry))
+    }
+}
+
 impl<'a> ObservationsToSources for CHQueryIterator<'a> {}
+
+pub struct CHArrayQueryIterator<'a> {
+    photometry: Photometry,
+    rows: BlockRows<'a>,
+    sorted: bool,
+    current_source: Option<Source>,
+}
+
+impl<'a> CHArrayQueryIterator<'a> {
+    fn new(query: CHQuery<'a>, sorted: bool) -> Self {
+        Self {
+            photometry: query.photometry.clone(),
+            rows: BlockRows::new(query),
+            sorted,
+            current_source: None,
+        }
+    }
+
+    /// Get array column both of Array(Float32) and Array(Float64) types
+    fn get_f32_array(row: &Row, col: &str) -> Vec<f32> {
+        match row.get::<Vec<f32>>(col) {
+            Ok(v) => v,
+            Err(_) => {
+                let v: Vec<f64> = row.get(col).unwrap();
+                v.into_iter().map(|x| x as f32).collect()
+            }
+        }
+    }
+
+    fn row_to_source(row: Row, photometry: &Photometry) -> Source {
+        let [value_col, err_col] = photometry.brightness.columns();
+        let sid: u64 = row.get("sid").unwrap();
+        let filter: u8 = row.get("filter").unwrap();
+        let mjd: Vec<f64> = row.get("mjd").unwrap();
+        let value = Self::get_f32_array(&row, value_col);
+        let err = Self::get_f32_array(&row, err_col);
+        assert!(
+            mjd.len() == value.len() && mjd.len() == err.len(),
+            "mjd, {} and {} arrays must have the same lengths",
+            value_col,
+            err_col
+        );
+        let mut source = Source::new(sid);
+        for ((mjd, value), err) in mjd.into_iter().zip(value).zip(err) {
+            source.push_observation(&photometry.observation(sid, filter, mjd, value, err));
+        }
+        source
+    }
+}
+
+impl<'a> Iterator for CHArrayQueryIterator<'a> {
+    type Item = Source;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let photometry = &self.photometry;
+        let row_to_source = |row: Row| Self::row_to_source(row, photometry);
+        let mut source = match self.current_source.take() {
+            Some(source) => source,
+            None => self.rows.next_with(row_to_source)?,
+        };
+        while let Some(next_source) = self.rows.next_with(row_to_source) {
+            if next_source.sid != source.sid {
+                self.current_source = Some(next_source);
+                break;
+            }
+            source.append(next_source);
+        }
+        if !self.sorted {
+            source.sort();
+        }
+        Some(source)
+    }
+}
+
+/// Number of rows inserted into ClickHouse by a single query
+const INSERT_BLOCK_SIZE: usize = 1 << 16;
+
+/// Inserts named f32 values of sources into ClickHouse table
+///
+/// The table has sid column and a Float32 column per value name, it is created if it doesn't exist
+/// and validated otherwise. ReplacingMergeTree engine removes duplicated sids of resumed runs
+pub struct CHSink {
+    client: ClientHandle,
+    table: String,
+    names: Vec<String>,
+    sids: Vec<u64>,
+    // Values by column
+    values: Vec<Vec<f32>>,
+}
+
+impl CHSink {
+    pub fn new(url: &str, table: &str, names: &[&str]) -> Self {
+        let pool = Pool::new(url);
+        let client = task::block_on(pool.get_handle()).unwrap();
+        let mut sink = Self {
+            client,
+            table: table.to_owned(),
+            names: names.iter().map(|&name| name.to_owned()).collect(),
+            sids: Vec::with_capacity(INSERT_BLOCK_SIZE),
+            values: names
+                .iter()
+                .map(|_| Vec::with_capacity(INSERT_BLOCK_SIZE))
+                .collect(),
+        };
+        task::block_on(sink.create_or_validate_table()).unwrap();
+        sink
+    }
+
+    fn columns(&self) -> Vec<(String, &'static str)> {
+        std::iter::once(("sid".to_owned(), "UInt64"))
+            .chain(self.names.iter().map(|name| (name.clone(), "Float32")))
+            .collect()
+    }
+
+    async fn create_or_validate_table(&mut self) -> Result<(), Error> {
+        let columns: Vec<_> = self
+            .columns()
+            .iter()
+            .map(|(name, type_)| format!("`{}` {}", name, type_))
+            .collect();
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} ({}) ENGINE = ReplacingMergeTree ORDER BY sid",
+            self.table,
+            columns.join(", ")
+        );
+        self.client.execute(query).await?;
+
+        let query = format!("DESCRIBE TABLE {}", self.table);
+        let block = self.client.query(query).fetch_all().await?;
+        let actual = block
+            .rows()
+            .map(|row| Ok((row.get("name")?, row.get("type")?)))
+            .collect::<Result<Vec<(String, String)>, Error>>()?;
+        let expected = self.columns();
+        assert!(
+            actual.len() == expected.len()
+                && actual.iter().zip(expected.iter()).all(
+                    |((a_name, a_type), (e_name, e_type))| a_name == e_name && a_type == e_type
+                ),
+            "ClickHouse table {} has columns {:?}, but {:?} are expected",
+            self.table,
+            actual,
+            expected
+        );
+        Ok(())
+    }
+
+    /// `data` is native-endian f32 values of the source
+    pub fn write(&mut self, sid: u64, data: &[u8]) {
+        assert_eq!(
+            data.len(),
+            4 * self.names.len(),
+            "unexpected number of values"
+        );
+        self.sids.push(sid);
+        for (column, bytes) in self.values.iter_mut().zip(data.chunks_exact(4)) {
+            column.push(f32::from_ne_bytes(bytes.try_into().unwrap()));
+        }
+        if self.sids.len() == INSERT_BLOCK_SIZE {
+            self.flush();
+        }
+    }
+
+    /// Insert buffered rows
+    pub fn flush(&mut self) {
+        if self.sids.is_empty() {
+            return;
+        }
+        let mut block = Block::new().column("sid", std::mem::take(&mut self.sids));
+        for (name, column) in self.names.iter().zip(self.values.iter_mut()) {
+            block = block.column(name, std::mem::take(column));
+        }
+        task::block_on(self.client.insert(self.table.as_str(), block))
+            .expect("Cannot insert values into ClickHouse");
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom};
+
+/// State of the outputs after some number of sources are written
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// Number of processed sources
+    pub count: usize,
+    /// sid of the last processed source
+    pub sid: Option<u64>,
+    /// Output file sizes by their paths
+    pub offsets: BTreeMap<String, u64>,
+}
+
+impl Checkpoint {
+    pub fn load(path: &str) -> Self {
+        let json = fs::read_to_string(path).expect("Cannot read checkpoint file");
+        Self::from_json(&json).unwrap()
+    }
+
+    /// Write checkpoint to a temporary file and rename it, so the file is never half-written
+    pub fn save(&self, path: &str) {
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, self.to_json()).expect("Cannot write checkpoint file");
+        fs::rename(&tmp_path, path).expect("Cannot write checkpoint file");
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "count": self.count,
+            "sid": self.sid,
+            "offsets": self.offsets,
+        })
+        .to_string()
+    }
+
+    fn from_json(json: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|err| format!("cannot parse checkpoint: {}", err))?;
+        let count = value["count"]
+            .as_u64()
+            .ok_or("checkpoint has no count of processed sources")? as usize;
+        let sid = value["sid"].as_u64();
+        let offsets = value["offsets"]
+            .as_object()
+            .ok_or("checkpoint has no output offsets")?
+            .iter()
+            .map(|(path, offset)| {
+                offset
+                    .as_u64()
+                    .map(|offset| (path.clone(), offset))
+                    .ok_or_else(|| format!("offset of {} is invalid", path))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            count,
+            sid,
+            offsets,
+        })
+    }
+
+    /// Truncate output file to its checkpoint size and open it for appending
+    pub fn appender(&self, path: &str) -> (BufWriter<File>, u64) {
+        let offset = *self
+            .offsets
+            .get(path)
+            .unwrap_or_else(|| panic!("Checkpoint has no offset for {}", path));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .expect("Cannot open output file to resume");
+        file.set_len(offset).expect("Cannot truncate output file");
+        file.seek(SeekFrom::End(0)).unwrap();
+        (BufWriter::new(file), offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_json_roundtrip() {
+        let checkpoint = Checkpoint {
+            count: 3,
+            sid: Some(u64::MAX),
+            offsets: [("sid.dat".to_owned(), 24), ("feature.dat".to_owned(), 0)]
+                .into_iter()
+                .collect(),
+        };
+        let json = checkpoint.to_json();
+        assert_eq!(Checkpoint::from_json(&json).unwrap(), checkpoint);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::lc::{LightCurve, Passband, PassbandRegistry, Source};
+
+/// Names of colour features of a passband pair, they are suffixed by passband names like "_g_r"
+const COLOR_FEATURE_NAMES: [&str; 5] = [
+    "mean_color",
+    "median_color",
+    "matched_color",
+    "color_slope",
+    "color_correlation",
+];
+
+/// Cross-band features of magnitude light curves of passband pairs
+///
+/// Colour is the magnitude difference of the first and the second passband. Matched epochs are
+/// pairs of observations closer in time than the tolerance, every observation in the first
+/// passband is matched to the nearest one in the second passband
+#[derive(Clone, Debug)]
+pub struct ColorFeatures {
+    pub pairs: Vec<(Passband, Passband)>,
+    /// Maximum time difference of matched epochs, days
+    pub tolerance: f32,
+}
+
+impl ColorFeatures {
+    /// Passband pairs from a string like "g-r,r-i"
+    pub fn parse_pairs(
+        registry: &PassbandRegistry,
+        pairs: &str,
+    ) -> Result<Vec<(Passband, Passband)>, String> {
+        pairs
+            .split(',')
+            .map(|pair| {
+                let (a, b) = pair
+                    .split_once('-')
+                    .ok_or_else(|| format!("colour {} must be specified as a-b", pair))?;
+                let find = |name: &str| {
+                    registry
+                        .find(name.trim())
+                        .ok_or_else(|| format!("colour {} has unknown passband {}", pair, name))
+                };
+                let (a, b) = (find(a)?, find(b)?);
+                if a == b {
+                    return Err(format!("colour {} has the same passbands", pair));
+                }
+                Ok((a, b))
+            })
+            .collect()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.pairs
+            .iter()
+            .flat_map(|(a, b)| {
+                COLOR_FEATURE_NAMES
+                    .iter()
+                    .map(move |name| format!("{}_{}_{}", name, a, b))
+            })
+            .collect()
+    }
+
+    /// JSON with pairs in the same format as --colors, so it can be read back by `from_json`
+    pub fn json(&self) -> serde_json::Value {
+        let pairs: Vec<_> = self
+            .pairs
+            .iter()
+            .map(|(a, b)| format!("{}-{}", a, b))
+            .collect();
+        serde_json::json!({
+            "pairs": pairs,
+            "tolerance": self.tolerance,
+        })
+    }
+
+    /// Colour features from "color" object of feature JSON, None if it has no such object
+    pub fn from_json(
+        registry: &PassbandRegistry,
+        json: &serde_json::Value,
+    ) -> Result<Option<Self>, String> {
+        let color = match &json["color"] {
+            serde_json::Value::Null => return Ok(None),
+            color => color,
+        };
+        let pairs: Vec<String> = serde_json::from_value(color["pairs"].clone())
+            .map_err(|err| format!("cannot parse colour pairs: {}", err))?;
+        let tolerance = color["tolerance"]
+            .as_f64()
+            .ok_or("colour tolerance must be a number")?;
+        Ok(Some(Self {
+            pairs: Self::parse_pairs(registry, &pairs.join(","))?,
+            tolerance: tolerance as f32,
+        }))
+    }
+
+    /// Values of all features, the ones undefined for the source are NaN, e.g. all features of
+    /// a pair having an empty passband or no positive native fluxes in it, or the slope and the correlation for less than two
+    /// matched epochs. Such sources are common, so they are not evaluation errors
+    pub fn eval(&self, source: &Source) -> Vec<f32> {
+        let mut values = Vec::with_capacity(self.pairs.len() * COLOR_FEATURE_NAMES.len());
+        for (a, b) in self.pairs.iter() {
+            let (lc_a, lc_b) = (source.lc(a).magn(), source.lc(b).magn());
+            let (lc_a, lc_b) = (lc_a.as_ref(), lc_b.as_ref());
+            if lc_a.t.is_empty() || lc_b.t.is_empty() {
+                values.extend_from_slice(&[f32::NAN; COLOR_FEATURE_NAMES.len()]);
+                continue;
+            }
+            values.push(weighted_mean(lc_a) - weighted_mean(lc_b));
+            values.push(median(&lc_a.mag) - median(&lc_b.mag));
+
+            let (t, mag_a, mag_b) = self.match_epochs(lc_a, lc_b);
+            let color: Vec<_> = mag_a.iter().zip(mag_b.iter()).map(|(a, b)| a - b).collect();
+            values.push(match color.len() {
+                0 => f32::NAN,
+                n => color.iter().sum::<f32>() / n as f32,
+            });
+            values.push(slope(&t, &color));
+            values.push(correlation(&mag_a, &mag_b));
+        }
+        values
+    }
+
+    /// Times and magnitudes of matched epochs, light curves must be sorted by time
+    fn match_epochs(&self, lc_a: &LightCurve, lc_b: &LightCurve) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+        let mut t = vec![];
+        let mut mag_a = vec![];
+        let mut mag_b = vec![];
+        for (&t_a, &m_a) in lc_a.t.iter().zip(lc_a.mag.iter()) {
+            let i = lc_b.t.partition_point(|&t_b| t_b < t_a);
+            let nearest = [i.checked_sub(1), Some(i)]
+                .into_iter()
+                .flatten()
+                .filter(|&j| j < lc_b.t.len())
+                .min_by(|&j, &k| (lc_b.t[j] - t_a).abs().total_cmp(&(lc_b.t[k] - t_a).abs()));
+            if let Some(j) = nearest {
+                if (lc_b.t[j] - t_a).abs() <= self.tolerance {
+                    t.push(0.5 * (t_a + lc_b.t[j]));
+                    mag_a.push(m_a);
+                    mag_b.push(lc_b.mag[j]);
+                }
+            }
+        }
+        (t, mag_a, mag_b)
+    }
+}
+
+fn weighted_mean(lc: &LightCurve) -> f32 {
+    let w_sum: f32 = lc.w.iter().sum();
+    let w_mag_sum: f32 = lc.w.iter().zip(lc.mag.iter()).map(|(w, m)| w * m).sum();
+    w_mag_sum / w_sum
+}
+
+fn median(x: &[f32]) -> f32 {
+    let mut sorted = x.to_vec();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+    let n = sorted.len();
+    0.5 * (sorted[(n - 1) / 2] + sorted[n / 2])
+}
+
+fn mean(x: &[f32]) -> f32 {
+    x.iter().sum::<f32>() / x.len() as f32
+}
+
+/// Least squares slope of y(x), NaN for less than two points or constant x
+fn slope(x: &[f32], y: &[f32]) -> f32 {
+    if x.len() < 2 {
+        return f32::NAN;
+    }
+    let (x_mean, y_mean) = (mean(x), mean(y));
+    let xy: f32 = x
+        .iter()
+        .zip(y.iter())
+        .map(|(x, y)| (x - x_mean) * (y - y_mean))
+        .sum();
+    let xx: f32 = x.iter().map(|x| (x - x_mean).powi(2)).sum();
+    xy / xx
+}
+
+/// Pearson correlation coefficient, NaN for less than two points or constant values
+fn correlation(x: &[f32], y: &[f32]) -> f32 {
+    if x.len() < 2 {
+        return f32::NAN;
+    }
+    let (x_mean, y_mean) = (mean(x), mean(y));
+    let xy: f32 = x
+        .iter()
+        .zip(y.iter())
+        .map(|(x, y)| (x - x_mean) * (y - y_mean))
+        .sum();
+    let xx: f32 = x.iter().map(|x| (x - x_mean).powi(2)).sum();
+    let yy: f32 = y.iter().map(|y| (y - y_mean).powi(2)).sum();
+    xy / (xx * yy).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::MAG_ZP_F32;
+    use crate::lc::{Observation, MJD0};
+
+    #[test]
+    fn color_features_eval() {
+        let registry = PassbandRegistry::default();
+        let color = ColorFeatures {
+            pairs: ColorFeatures::parse_pairs(&registry, "g-r").unwrap(),
+            tolerance: 0.1,
+        };
+        assert_eq!(color.names()[0], "mean_color_g_r");
+
+        let mut source = Source::new(0);
+        for (t, mag) in [(0.0, 15.0), (1.0, 15.2), (2.0, 15.4), (3.0, 15.6)] {
+            source.push_observation(&Observation::from_mjd_magerr(
+                0,
+                1,
+                MJD0 + t,
+                mag,
+                1.0,
+                MAG_ZP_F32,
+            ));
+        }
+        // The last observation has no match in g
+        for (t, mag) in [(0.05, 14.5), (1.0, 14.6), (2.05, 14.7), (5.0, 14.8)] {
+            source.push_observation(&Observation::from_mjd_magerr(
+                0,
+                2,
+                MJD0 + t,
+                mag,
+                1.0,
+                MAG_ZP_F32,
+            ));
+        }
+        let values = color.eval(&source);
+        let expected = [0.65, 0.65, 0.6, 0.1, 1.0];
+        for (value, expected) in values.iter().zip(expected.iter()) {
+            assert!((value - expected).abs() < 1e-4, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn color_features_undefined_are_nan() {
+        let registry = PassbandRegistry::default();
+        let color = ColorFeatures {
+            pairs: ColorFeatures::parse_pairs(&registry, "g-r,g-i").unwrap(),
+            tolerance: 0.1,
+        };
+        let mut source = Source::new(0);
+        for (passband, t) in [(1, 0.0), (1, 1.0), (2, 0.0), (2, 5.0)] {
+            source.push_observation(&Observation::from_mjd_magerr(
+                0,
+                passband,
+                MJD0 + t,
+                15.0,
+                1.0,
+                MAG_ZP_F32,
+            ));
+        }
+        let values = color.eval(&source);
+        // Single matched epoch of g-r
+        assert!(values[0].is_finite());
+        assert!(values[3].is_nan());
+        // No observations in i
+        assert!(values[5..].iter().all(|x| x.is_nan()));
+    }
+
+    #[test]
+    fn color_features_skip_negative_flux() {
+        let registry = PassbandRegistry::default();
+        let color = ColorFeatures {
+            pairs: ColorFeatures::parse_pairs(&registry, "g-r").unwrap(),
+            tolerance: 0.1,
+        };
+        let mut source = Source::new(0);
+        for (passband, t, mag) in [
+            (1, 0.0, 15.0),
+            (1, 1.0, 15.0),
+            (2, 0.0, 14.0),
+            (2, 1.0, 14.0),
+        ] {
+            source.push_observation(&Observation::from_mjd_magerr(
+                0,
+                passband,
+                MJD0 + t,
+                mag,
+                1.0,
+                MAG_ZP_F32,
+            ));
+        }
+        // Matches the second r observation too
+        source.push_observation(&Observation::from_mjd_fluxerr(
+            0,
+            1,
+            MJD0 + 1.05,
+            -1.0,
+            1.0,
+            MAG_ZP_F32,
+        ));
+        let values = color.eval(&source);
+        assert!(values[..3].iter().all(|&x| x == 1.0), "{:?}", values);
+        assert_eq!(values[3], 0.0);
+    }
+
+    #[test]
+    fn color_pairs_from_str_invalid() {
+        let registry = PassbandRegistry::default();
+        assert!(ColorFeatures::parse_pairs(&registry, "gr").is_err());
+        assert!(ColorFeatures::parse_pairs(&registry, "g-g").is_err());
+        assert!(ColorFeatures::parse_pairs(&registry, "g-x").is_err());
+    }
+
+    #[test]
+    fn color_features_json_roundtrip() {
+        let registry = PassbandRegistry::default();
+        let color = ColorFeatures {
+            pairs: ColorFeatures::parse_pairs(&registry, "g-r,r-i").unwrap(),
+            tolerance: 0.25,
+        };
+        let json = serde_json::json!({ "color": color.json() });
+        let restored = ColorFeatures::from_json(&registry, &json).unwrap().unwrap();
+        assert_eq!(restored.pairs, color.pairs);
+        assert_eq!(restored.tolerance, color.tolerance);
+        assert!(ColorFeatures::from_json(&registry, &serde_json::json!({}))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::config::FileConfig;
+use crate::lc::{Observation, Photometry};
+use crate::traits::{ObservationsToSources, SourceDataBase};
+
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter};
+use std::fs::File;
+use std::io::{self, Read};
+
+pub struct CsvSourceDataBase {
+    config: FileConfig,
+}
+
+impl CsvSourceDataBase {
+    pub fn new(config: &FileConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl<'a> SourceDataBase<'a> for CsvSourceDataBase {
+    type Query = CsvQuery;
+
+    /// `path` is a path to the file or "-" for stdin
+    fn query(&'a mut self, path: &str) -> Self::Query {
+        let reader: Box<dyn Read> = match path {
+            "-" => Box::new(io::stdin()),
+            _ => Box::new(File::open(path).expect("Cannot open input file")),
+        };
+        CsvQuery::new(reader, &self.config)
+    }
+}
+
+pub struct CsvQuery {
+    records: StringRecordsIntoIter<Box<dyn Read>>,
+    first_record: Option<StringRecord>,
+    indices: [usize; 5],
+    photometry: Photometry,
+}
+
+impl CsvQuery {
+    pub fn new(reader: Box<dyn Read>, config: &FileConfig) -> Self {
+        let mut records = ReaderBuilder::new()
+            .delimiter(config.delimiter)
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .into_records();
+        let first_record = records
+            .next()
+            .map(|record| record.expect("Cannot parse the first line of the input file"));
+        let (first_record, indices) = match first_record {
+            Some(record) if Self::is_header(&record) => {
+                let indices = Self::column_indices(&record, &config.columns);
+                (None, indices)
+            }
+            record => (record, [0, 1, 2, 3, 4]),
+        };
+        Self {
+            records,
+            first_record,
+            indices,
+            photometry: config.photometry.clone(),
+        }
+    }
+
+    /// Header is a record having at least one non-numeric value
+    fn is_header(record: &StringRecord) -> bool {
+        record.iter().any(|value| value.parse::<f64>().is_err())
+    }
+
+    fn column_indices(header: &StringRecord, columns: &[String; 5]) -> [usize; 5] {
+        let mut indices = [0; 5];
+        for (index, column) in indices.iter_mut().zip(columns.iter()) {
+            *index = header
+                .iter()
+                .position(|name| name == column)
+                .unwrap_or_else(|| panic!("Column {} is not found in the file header", column));
+        }
+        indices
+    }
+}
+
+impl IntoIterator for CsvQuery {
+    type Item = Observation;
+    type IntoIter = CsvQueryIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Self::IntoIter::new(self)
+    }
+}
+
+pub struct CsvQueryIterator {
+    query: CsvQuery,
+}
+
+impl CsvQueryIterator {
+    fn new(query: CsvQuery) -> Self {
+        Self { query }
+    }
+
+    fn get<T>(record: &StringRecord, idx: usize) -> T
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Debug,
+    {
+        record
+            .get(idx)
+            .unwrap_or_else(|| panic!("Line {:?} has no column #{}", record, idx))
+            .parse()
+            .unwrap_or_else(|err| panic!("Cannot parse column #{} of {:?}: {:?}", idx, record, err))
+    }
+
+    fn record_to_obs(&self, record: StringRecord) -> Observation {
+        let [sid_idx, mjd_idx, filter_idx, value_idx, err_idx] = self.query.indices;
+        self.query.photometry.observation(
+            Self::get(&record, sid_idx),
+            Self::get(&record, filter_idx),
+            Self::get(&record, mjd_idx),
+            Self::get(&record, value_idx),
+            Self::get(&record, err_idx),
+        )
+    }
+}
+
+impl Iterator for CsvQueryIterator {
+    type Item = Observation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.query.first_record.take() {
+            Some(record) => record,
+            None => self
+                .query
+                .records
+                .next()?
+                .expect("Cannot parse line of the input file"),
+        };
+        Some(self.record_to_obs(record))
+    }
+}
+
+impl ObservationsToSources for CsvQueryIterator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lc::Brightness;
+
+    fn observations(data: &'static str, config: &FileConfig) -> Vec<Observation> {
+        CsvQuery::new(Box::new(data.as_bytes()), config)
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn csv_without_header() {
+        let config = FileConfig::default();
+        let obs = observations("1,58001.5,1,15.0,0.5\n1,58002.5,2,16.0,0.25\n", &config);
+        assert_eq!(obs.len(), 2);
+        assert_eq!(obs[0].sid, 1);
+        assert_eq!(obs[0].t, 1.5);
+        assert_eq!(obs[0].w, 4.0);
+        assert_eq!(obs[1].passband, 2);
+        assert_eq!(obs[1].mag, 16.0);
+    }
+
+    #[test]
+    fn tsv_with_custom_header() {
+        let config = FileConfig {
+            delimiter: b'\t',
+            photometry: Photometry::default(),
+            columns: [
+                "oid".to_owned(),
+                "mjd".to_owned(),
+                "filter".to_owned(),
+                "mag".to_owned(),
+                "magerr".to_owned(),
+            ],
+        };
+        let obs = observations(
+            "mag\tmagerr\tfilter\tmjd\toid\n15.0\t0.5\t3\t58001.5\t7\n",
+            &config,
+        );
+        assert_eq!(obs.len(), 1);
+        assert_eq!(obs[0].sid, 7);
+        assert_eq!(obs[0].t, 1.5);
+        assert_eq!(obs[0].passband, 3);
+    }
+
+    #[test]
+    fn csv_flux() {
+        let config = FileConfig {
+            photometry: Photometry {
+                brightness: Brightness::Flux,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let obs = observations("1,58001.5,1,-1.0,0.5\n", &config);
+        assert_eq!(obs[0].flux, -1.0);
+        assert_eq!(obs[0].flux_w, 4.0);
+        assert!(obs[0].mag.is_nan());
+    }
+
+    #[test]
+    #[should_panic]
+    fn csv_header_missing_column_panic() {
+        let config = FileConfig::default();
+        let _obs = observations("sid,mjd,band,mag,magerr\n", &config);
+    }
+}
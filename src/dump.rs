@@ -1,42 +1,118 @@
-use crate::constants::MAG_ZP_F32;
-use crate::lc::{Passband, Source};
+use crate::affinity;
+use crate::ch::CHSink;
+use crate::checkpoint::Checkpoint;
+use crate::color::ColorFeatures;
+use crate::config::{
+    FailurePolicy, InterpolationFill, OutputFormat, ProgressConfig, ThreadConfig, TimeOffset,
+};
+#[cfg(feature = "hdf")]
+use crate::hdf::Hdf5ValueWriter;
+use crate::lc::{Passband, Source, ZeroPoints, MJD0};
+use crate::metrics::Metrics;
+use crate::npy::{NpyHeader, HEADER_LEN as NPY_HEADER_LEN};
+#[cfg(feature = "arrow")]
+use crate::parquet_dump::ParquetValueWriter;
+use crate::progress::ProgressReporter;
+use crate::quality::{QualityFilter, RejectionCounts, SourceSelection};
 use crate::traits::*;
 
 use crossbeam::channel::{bounded as bounded_channel, Receiver, Sender};
 use light_curve_feature::{Feature, FeatureEvaluator, FeatureNamesDescriptionsTrait, TimeSeries};
 use light_curve_interpol::Interpolator;
-use num_cpus;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::iter::Iterator;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::thread;
-
-fn mag_to_flux(mag: f32) -> f32 {
-    10_f32.powf(-0.4 * (mag - MAG_ZP_F32))
-}
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 struct FluxDump {
     path: String,
+    json_path: String,
+    mjd_grid: Vec<f64>,
     interpolator: Interpolator<f32, f32>,
+    fill: InterpolationFill,
     passbands: Vec<Passband>,
+    json: String,
+}
+
+impl FluxDump {
+    fn new(
+        path: String,
+        json_path: String,
+        mjd_grid: Vec<f64>,
+        fill: InterpolationFill,
+        passbands: Vec<Passband>,
+        zero_points: &ZeroPoints,
+    ) -> Self {
+        let fill_value = match fill {
+            InterpolationFill::Zero => 0.0,
+            InterpolationFill::NaN | InterpolationFill::Edge => f32::NAN,
+        };
+        let interpolator = Interpolator {
+            target_x: mjd_grid.iter().map(|&mjd| (mjd - MJD0) as f32).collect(),
+            left: fill_value,
+            right: fill_value,
+        };
+        let json = serde_json::json!({
+            "mjd": &mjd_grid,
+            "fill": fill.to_string(),
+            "passbands": passbands.iter().map(|passband| passband.to_string()).collect::<Vec<_>>(),
+            "shape": [passbands.len(), mjd_grid.len()],
+            "zero_point": zero_points.json(&passbands),
+        })
+        .to_string();
+        Self {
+            path,
+            json_path,
+            mjd_grid,
+            interpolator,
+            fill,
+            passbands,
+            json,
+        }
+    }
 }
 
 impl Dump for FluxDump {
-    fn eval(&self, source: &Source) -> Vec<u8> {
+    fn eval(&self, source: &Source) -> EvalResult {
         let mut result = vec![];
-        for &passband in self.passbands.iter() {
+        // Grid relative to the time offset of the source
+        let interpolator = match source.t0 == 0.0 {
+            true => Cow::Borrowed(&self.interpolator),
+            false => Cow::Owned(Interpolator {
+                target_x: self
+                    .mjd_grid
+                    .iter()
+                    .map(|&mjd| (mjd - MJD0 - source.t0) as f32)
+                    .collect(),
+                left: self.interpolator.left,
+                right: self.interpolator.right,
+            }),
+        };
+        for passband in self.passbands.iter() {
             let lc = source.lc(passband);
-            let flux: Vec<_> = lc.mag.iter().copied().map(mag_to_flux).collect();
-            self.interpolator
-                .interpolate(&lc.t[..], &flux[..])
-                .iter()
-                .for_each(|x| {
-                    let bytes = x.to_bits().to_ne_bytes();
-                    result.extend_from_slice(&bytes);
-                });
+            let flux = &lc.flux;
+            let interpolated = match (self.fill, flux.first(), flux.last()) {
+                (InterpolationFill::Edge, Some(&left), Some(&right)) => Interpolator {
+                    target_x: interpolator.target_x.clone(),
+                    left,
+                    right,
+                }
+                .interpolate(&lc.t[..], &flux[..]),
+                _ => interpolator.interpolate(&lc.t[..], &flux[..]),
+            };
+            interpolated.iter().for_each(|x| {
+                let bytes = x.to_bits().to_ne_bytes();
+                result.extend_from_slice(&bytes);
+            });
         }
-        result
+        result.into()
     }
 
     fn get_names(&self) -> Vec<&str> {
@@ -44,19 +120,35 @@ impl Dump for FluxDump {
     }
 
     fn get_json(&self) -> &str {
-        ""
+        self.json.as_str()
     }
 
     fn get_value_path(&self) -> &str {
         self.path.as_str()
     }
 
+    fn get_value_name(&self) -> &str {
+        "flux"
+    }
+
+    fn get_value_dtype(&self) -> &str {
+        "f4"
+    }
+
+    fn get_value_shape(&self) -> Vec<usize> {
+        vec![self.passbands.len() * self.interpolator.target_x.len()]
+    }
+
+    fn get_value_grid(&self) -> Option<&[f64]> {
+        Some(&self.mjd_grid)
+    }
+
     fn get_name_path(&self) -> Option<&str> {
         None
     }
 
     fn get_json_path(&self) -> Option<&str> {
-        None
+        Some(self.json_path.as_str())
     }
 }
 
@@ -66,28 +158,38 @@ struct FeatureDump {
     name_path: String,
     json_path: String,
     magn_feature_extractor: Feature<f32>,
-    flux_feature_extractor: Feature<f32>,
+    /// Flux feature extractor of every passband, they differ by zero points only
+    flux_feature_extractors: Vec<Feature<f32>>,
+    color_features: Option<ColorFeatures>,
     passbands: Vec<Passband>,
     names: Vec<String>,
     json: String,
 }
 
 impl FeatureDump {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         value_path: String,
         name_path: String,
         json_path: String,
         magn_feature_extractor: Feature<f32>,
-        flux_feature_extractor: Feature<f32>,
+        flux_feature_extractors: Vec<Feature<f32>>,
+        color_features: Option<ColorFeatures>,
         passbands: Vec<Passband>,
+        zero_points: &ZeroPoints,
     ) -> Self {
+        assert_eq!(
+            flux_feature_extractors.len(),
+            passbands.len(),
+            "flux feature extractor must be specified for every passband"
+        );
         let magn_feature_extractor_names = magn_feature_extractor.get_names();
-        let flux_feature_extractor_names = flux_feature_extractor.get_names();
+        let flux_feature_extractor_names = flux_feature_extractors[0].get_names();
         let extr_names_types = [
             (&magn_feature_extractor_names, "magn"),
             (&flux_feature_extractor_names, "flux"),
         ];
-        let names = passbands
+        let mut names: Vec<_> = passbands
             .iter()
             .flat_map(|passband| {
                 extr_names_types.iter().flat_map(
@@ -99,20 +201,34 @@ impl FeatureDump {
                 )
             })
             .collect();
-        let json = serde_json::json!({
+        let flux_feature_extractor_json = match zero_points.uniform(&passbands) {
+            Some(_) => serde_json::json!(&flux_feature_extractors[0]),
+            None => passbands
+                .iter()
+                .zip(flux_feature_extractors.iter())
+                .map(|(passband, extractor)| (passband.to_string(), serde_json::json!(extractor)))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        };
+        let mut json = serde_json::json!({
             "magn": &magn_feature_extractor,
             "flux": {
-                "extractor": &flux_feature_extractor,
-                "zero_point": MAG_ZP_F32,
+                "extractor": flux_feature_extractor_json,
+                "zero_point": zero_points.json(&passbands),
                 }
-        })
-        .to_string();
+        });
+        if let Some(color_features) = &color_features {
+            names.extend(color_features.names());
+            json["color"] = color_features.json();
+        }
+        let json = json.to_string();
         Self {
             value_path,
             name_path,
             json_path,
             magn_feature_extractor,
-            flux_feature_extractor,
+            flux_feature_extractors,
+            color_features,
             passbands,
             names,
             json,
@@ -121,33 +237,40 @@ impl FeatureDump {
 }
 
 impl Dump for FeatureDump {
-    fn eval(&self, source: &Source) -> Vec<u8> {
+    fn eval(&self, source: &Source) -> EvalResult {
         let mut result = vec![];
-        for &passband in self.passbands.iter() {
+        let mut errors = vec![];
+        for (passband, flux_feature_extractor) in self
+            .passbands
+            .iter()
+            .zip(self.flux_feature_extractors.iter())
+        {
             let lc = source.lc(passband);
-            let flux: Vec<_> = lc.mag.iter().copied().map(mag_to_flux).collect();
-            let flux_weight: Vec<_> = flux
-                .iter()
-                .zip(lc.w.iter())
-                .map(|(f, w_m)| w_m / f32::powi(0.4 * f32::ln(10.0) * f, 2))
-                .collect();
-            let ts_magn = TimeSeries::new(&lc.t, &lc.mag, &lc.w);
-            let ts_flux = TimeSeries::new(&lc.t, &flux, &flux_weight);
-            for (feature_extractor, ts) in &mut [
-                (&self.magn_feature_extractor, ts_magn),
-                (&self.flux_feature_extractor, ts_flux),
+            let magn_lc = lc.magn();
+            let ts_magn = TimeSeries::new(&magn_lc.t, &magn_lc.mag, &magn_lc.w);
+            let ts_flux = TimeSeries::new(&lc.t, &lc.flux, &lc.flux_w);
+            for (feature_extractor, brightness_type, ts) in &mut [
+                (&self.magn_feature_extractor, "magn", ts_magn),
+                (flux_feature_extractor, "flux", ts_flux),
             ] {
-                feature_extractor
-                    .eval(ts)
-                    .expect("Some feature cannot be extracted")
-                    .iter()
-                    .for_each(|x| {
-                        let bytes = x.to_bits().to_ne_bytes();
-                        result.extend_from_slice(&bytes);
-                    });
+                let values = feature_extractor.eval(ts).unwrap_or_else(|err| {
+                    errors.push(format!("{}_{}: {}", brightness_type, passband, err));
+                    vec![f32::NAN; feature_extractor.get_names().len()]
+                });
+                values.iter().for_each(|x| {
+                    let bytes = x.to_bits().to_ne_bytes();
+                    result.extend_from_slice(&bytes);
+                });
             }
         }
-        result
+        if let Some(color_features) = &self.color_features {
+            let values = color_features.eval(source);
+            result.extend(values.iter().flat_map(|x| x.to_ne_bytes()));
+        }
+        EvalResult {
+            data: result,
+            errors,
+        }
     }
 
     fn get_names(&self) -> Vec<&str> {
@@ -162,6 +285,22 @@ impl Dump for FeatureDump {
         self.value_path.as_str()
     }
 
+    fn get_value_name(&self) -> &str {
+        "features"
+    }
+
+    fn get_value_dtype(&self) -> &str {
+        "f4"
+    }
+
+    fn get_value_shape(&self) -> Vec<usize> {
+        vec![self.names.len()]
+    }
+
+    fn get_value_grid(&self) -> Option<&[f64]> {
+        None
+    }
+
     fn get_name_path(&self) -> Option<&str> {
         Some(self.name_path.as_str())
     }
@@ -177,8 +316,56 @@ struct SIDDump {
 }
 
 impl Dump for SIDDump {
-    fn eval(&self, source: &Source) -> Vec<u8> {
-        source.sid.to_ne_bytes().to_vec()
+    fn eval(&self, source: &Source) -> EvalResult {
+        source.sid.to_ne_bytes().to_vec().into()
+    }
+
+    fn get_names(&self) -> Vec<&str> {
+        vec![]
+    }
+
+    fn get_json(&self) -> &str {
+        ""
+    }
+
+    fn get_value_path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    fn get_value_name(&self) -> &str {
+        "sid"
+    }
+
+    fn get_value_dtype(&self) -> &str {
+        "u8"
+    }
+
+    fn get_value_shape(&self) -> Vec<usize> {
+        vec![]
+    }
+
+    fn get_value_grid(&self) -> Option<&[f64]> {
+        None
+    }
+
+    fn get_name_path(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_json_path(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// MJD of the time offset of every source
+#[derive(Clone)]
+struct T0Dump {
+    path: String,
+}
+
+impl Dump for T0Dump {
+    fn eval(&self, source: &Source) -> EvalResult {
+        (MJD0 + source.t0).to_ne_bytes().to_vec().into()
     }
 
     fn get_names(&self) -> Vec<&str> {
@@ -193,6 +380,22 @@ impl Dump for SIDDump {
         self.path.as_str()
     }
 
+    fn get_value_name(&self) -> &str {
+        "t0"
+    }
+
+    fn get_value_dtype(&self) -> &str {
+        "f8"
+    }
+
+    fn get_value_shape(&self) -> Vec<usize> {
+        vec![]
+    }
+
+    fn get_value_grid(&self) -> Option<&[f64]> {
+        None
+    }
+
     fn get_name_path(&self) -> Option<&str> {
         None
     }
@@ -202,18 +405,402 @@ impl Dump for SIDDump {
     }
 }
 
+/// Sequence number, sid and evaluation result of a source sent to the writer, `Err` of the outer
+/// result is a panic payload of the eval worker, `Err` of the inner one means the source is skipped
+type EvalMessage = (usize, u64, thread::Result<Result<Vec<EvalResult>, String>>);
+
+/// Collects out-of-order evaluation results and yields them by sequence number
+struct ReorderBuffer<T> {
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> ReorderBuffer<T> {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, seq: usize, item: T) {
+        assert!(
+            seq >= self.next,
+            "sequence number {} is already written",
+            seq
+        );
+        self.pending.insert(seq, item);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let item = self.pending.remove(&self.next)?;
+        self.next += 1;
+        Some(item)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Numbers of sources at every stage of a run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceCounts {
+    /// Sources written by the interrupted run and skipped on resume
+    pub resumed: usize,
+    /// Sources sent to evaluation
+    pub read: usize,
+    /// Sources skipped by source selection
+    pub skipped: usize,
+    /// Sources having evaluation errors, they are written only with fill failure policy
+    pub failed: usize,
+    /// Sources written to value outputs
+    pub written: usize,
+}
+
+/// Array stored in an output file, a row per written source
+#[derive(Clone, Debug)]
+pub struct OutputArray {
+    pub name: String,
+    /// NumPy type string, like "<f4"
+    pub descr: String,
+    /// Shape of a single row, it is empty for 1-D array
+    pub row_shape: Vec<usize>,
+}
+
+/// Output file and arrays it stores, text outputs have no arrays
+#[derive(Clone, Debug)]
+pub struct OutputFile {
+    pub path: String,
+    pub arrays: Vec<OutputArray>,
+}
+
+/// Output file of `DumpWriter`
+enum ValueWriter {
+    File(BufWriter<File>),
+    #[cfg(feature = "arrow")]
+    Parquet(Box<ParquetValueWriter>),
+    #[cfg(feature = "hdf")]
+    Hdf5(Box<Hdf5ValueWriter>),
+}
+
+impl ValueWriter {
+    #[cfg_attr(not(feature = "arrow"), allow(unused_variables))]
+    fn write(&mut self, sid: u64, data: &[u8]) {
+        match self {
+            Self::File(writer) => writer.write_all(data).expect("Cannot write to file"),
+            #[cfg(feature = "arrow")]
+            Self::Parquet(writer) => writer.write(sid, data),
+            #[cfg(feature = "hdf")]
+            Self::Hdf5(writer) => writer.write(data),
+        }
+    }
+
+    /// Flush file buffer, Parquet rows are kept until the row group is full
+    fn flush(&mut self) {
+        match self {
+            Self::File(writer) => writer.flush().expect("Cannot write to file"),
+            #[cfg(feature = "arrow")]
+            Self::Parquet(_) => {}
+            #[cfg(feature = "hdf")]
+            Self::Hdf5(writer) => writer.flush(),
+        }
+    }
+
+    fn close(mut self) {
+        self.flush();
+        match self {
+            Self::File(_) => {}
+            #[cfg(feature = "arrow")]
+            Self::Parquet(writer) => writer.close(),
+            #[cfg(feature = "hdf")]
+            Self::Hdf5(writer) => writer.close(),
+        }
+    }
+}
+
+/// Writes evaluation results of sources to output files and tracks their sizes for checkpoints
+struct DumpWriter {
+    paths: Vec<String>,
+    writers: Vec<ValueWriter>,
+    offsets: Vec<u64>,
+    // Headers of npy outputs, they are rewritten when outputs are synced
+    npy_headers: Vec<Option<NpyHeader>>,
+    // Index of failure log in paths, writers and offsets
+    failure_idx: Option<usize>,
+    // Index of skipped source list in paths, writers and offsets
+    skipped_idx: Option<usize>,
+    // ClickHouse sink and index of the dump it inserts
+    ch_sink: Option<(usize, CHSink)>,
+    failure_policy: FailurePolicy,
+    checkpoint: Option<(String, Duration)>,
+    last_checkpoint: Instant,
+    count: usize,
+    last_sid: Option<u64>,
+    counts: SourceCounts,
+}
+
+impl DumpWriter {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        dumps: &[Box<dyn Dump>],
+        output_format: OutputFormat,
+        failure_policy: FailurePolicy,
+        failure_path: Option<String>,
+        skipped_path: Option<String>,
+        ch_sink: Option<(String, String)>,
+        checkpoint: Option<(String, Duration)>,
+        resume: Option<&Checkpoint>,
+    ) -> Self {
+        let mut paths: Vec<_> = dumps
+            .iter()
+            .map(|dump| dump.get_value_path().to_owned())
+            .collect();
+        let mut npy_headers: Vec<_> = dumps
+            .iter()
+            .map(|dump| match output_format {
+                OutputFormat::Dat => None,
+                OutputFormat::Npy => Some(NpyHeader::native(
+                    dump.get_value_dtype(),
+                    &dump.get_value_shape(),
+                )),
+                #[cfg(feature = "arrow")]
+                OutputFormat::Parquet => None,
+                #[cfg(feature = "hdf")]
+                OutputFormat::Hdf5 => None,
+            })
+            .collect();
+        // All dumps share the same HDF5 file
+        #[cfg(feature = "hdf")]
+        let hdf5_file = match (output_format, dumps.first()) {
+            (OutputFormat::Hdf5, Some(dump)) => {
+                let path = dump.get_value_path();
+                assert!(
+                    dumps.iter().all(|dump| dump.get_value_path() == path),
+                    "HDF5 output must be a single file"
+                );
+                Some(hdf5::File::create(path).expect("Cannot create HDF5 output file"))
+            }
+            _ => None,
+        };
+        let mut push_log = |path| {
+            paths.push(path);
+            npy_headers.push(None);
+            paths.len() - 1
+        };
+        let failure_idx = failure_path.map(&mut push_log);
+        let skipped_idx = skipped_path.map(&mut push_log);
+        let (writers, offsets) = paths
+            .iter()
+            .enumerate()
+            .map(|(idx, path)| {
+                #[cfg(feature = "arrow")]
+                if output_format == OutputFormat::Parquet && idx < dumps.len() {
+                    return (Self::parquet_writer(dumps[idx].as_ref()), 0);
+                }
+                #[cfg(feature = "hdf")]
+                if let (Some(file), true) = (&hdf5_file, idx < dumps.len()) {
+                    let writer = Hdf5ValueWriter::new(file, dumps[idx].as_ref());
+                    return (ValueWriter::Hdf5(Box::new(writer)), 0);
+                }
+                match (resume, &npy_headers[idx]) {
+                    // Resumed outputs already have headers
+                    (Some(checkpoint), _) => {
+                        let (writer, offset) = checkpoint.appender(path);
+                        (ValueWriter::File(writer), offset)
+                    }
+                    (None, Some(header)) => {
+                        let mut writer = Dumper::writer_from_path(path);
+                        writer
+                            .write_all(&header.to_bytes())
+                            .expect("Cannot write to file");
+                        (ValueWriter::File(writer), NPY_HEADER_LEN as u64)
+                    }
+                    (None, None) => (ValueWriter::File(Dumper::writer_from_path(path)), 0),
+                }
+            })
+            .unzip();
+        Self {
+            paths,
+            writers,
+            offsets,
+            npy_headers,
+            failure_idx,
+            skipped_idx,
+            ch_sink: ch_sink.map(|(url, table)| Self::ch_sink(dumps, &url, &table)),
+            failure_policy,
+            checkpoint,
+            last_checkpoint: Instant::now(),
+            count: resume.map_or(0, |checkpoint| checkpoint.count),
+            last_sid: resume.and_then(|checkpoint| checkpoint.sid),
+            counts: SourceCounts::default(),
+        }
+    }
+
+    /// Parquet output has sid column, so dump values must be f32
+    #[cfg(feature = "arrow")]
+    fn parquet_writer(dump: &dyn Dump) -> ValueWriter {
+        assert_eq!(
+            dump.get_value_dtype(),
+            "f4",
+            "only f32 values can be written to Parquet"
+        );
+        let row_len = dump.get_value_shape().iter().product();
+        ValueWriter::Parquet(Box::new(ParquetValueWriter::new(
+            dump.get_value_path(),
+            dump.get_value_name(),
+            &dump.get_names(),
+            row_len,
+            dump.get_json(),
+        )))
+    }
+
+    /// ClickHouse sink of the feature dump
+    fn ch_sink(dumps: &[Box<dyn Dump>], url: &str, table: &str) -> (usize, CHSink) {
+        let idx = dumps
+            .iter()
+            .position(|dump| dump.get_value_name() == "features")
+            .expect("ClickHouse sink requires feature extraction");
+        (idx, CHSink::new(url, table, &dumps[idx].get_names()))
+    }
+
+    fn write_to(&mut self, idx: usize, sid: u64, data: &[u8]) {
+        self.writers[idx].write(sid, data);
+        self.offsets[idx] += data.len() as u64;
+    }
+
+    /// `results` is Err with the reason if the source is skipped by source selection
+    fn write(&mut self, sid: u64, results: Result<Vec<EvalResult>, String>) {
+        self.count += 1;
+        self.last_sid = Some(sid);
+        match results {
+            Ok(results) => self.write_results(sid, results),
+            Err(reason) => {
+                self.counts.skipped += 1;
+                if let Some(idx) = self.skipped_idx {
+                    let line = format!("{}\t{}\n", sid, reason);
+                    self.write_to(idx, sid, line.as_bytes());
+                }
+            }
+        }
+        if let Some((_, interval)) = self.checkpoint {
+            if self.last_checkpoint.elapsed() >= interval {
+                self.sync();
+            }
+        }
+    }
+
+    fn write_results(&mut self, sid: u64, results: Vec<EvalResult>) {
+        let errors: Vec<_> = results.iter().flat_map(|result| &result.errors).collect();
+        if !errors.is_empty() {
+            self.counts.failed += 1;
+            if let Some(idx) = self.failure_idx {
+                for error in errors.iter() {
+                    let line = format!("{}\t{}\n", sid, error);
+                    self.write_to(idx, sid, line.as_bytes());
+                }
+            }
+            match self.failure_policy {
+                FailurePolicy::Fill => {}
+                FailurePolicy::Skip => return,
+                FailurePolicy::Abort => {
+                    panic!("Source {} cannot be evaluated: {:?}", sid, errors)
+                }
+            }
+        }
+        for (idx, result) in results.iter().enumerate() {
+            self.write_to(idx, sid, &result.data[..]);
+        }
+        self.counts.written += 1;
+        if let Some((idx, sink)) = &mut self.ch_sink {
+            sink.write(sid, &results[*idx].data[..]);
+        }
+    }
+
+    /// Flush outputs, rewrite npy headers and save checkpoint if it is required
+    fn sync(&mut self) {
+        if let Some((_, sink)) = &mut self.ch_sink {
+            sink.flush();
+        }
+        for (idx, writer) in self.writers.iter_mut().enumerate() {
+            writer.flush();
+            if let (Some(header), ValueWriter::File(writer)) = (&mut self.npy_headers[idx], writer)
+            {
+                header.set_rows_from_data_len(self.offsets[idx] as usize - NPY_HEADER_LEN);
+                header.rewrite(writer.get_mut());
+            }
+        }
+        let path = match &self.checkpoint {
+            Some((path, _)) => path.clone(),
+            None => return,
+        };
+        let checkpoint = Checkpoint {
+            count: self.count,
+            sid: self.last_sid,
+            offsets: self
+                .paths
+                .iter()
+                .cloned()
+                .zip(self.offsets.iter().copied())
+                .collect(),
+        };
+        checkpoint.save(&path);
+        self.last_checkpoint = Instant::now();
+    }
+
+    /// Returns numbers of skipped, failed and written sources
+    fn finish(mut self) -> SourceCounts {
+        self.sync();
+        for writer in self.writers {
+            writer.close();
+        }
+        self.counts
+    }
+}
+
 pub struct Dumper {
     passbands: Vec<Passband>,
+    zero_points: ZeroPoints,
     dumps: Vec<Box<dyn Dump + 'static>>,
+    output_format: OutputFormat,
+    ordered: bool,
+    failure_policy: FailurePolicy,
+    failure_path: Option<String>,
+    ch_sink: Option<(String, String)>,
+    checkpoint: Option<(String, Duration)>,
+    resume: Option<Checkpoint>,
+    source_selection: Option<SourceSelection>,
+    skipped_path: Option<String>,
+    quality_filter: Option<QualityFilter>,
+    time_offset: TimeOffset,
+    // Progress reports and total number of sources if it is known
+    progress: Option<(ProgressConfig, Option<usize>)>,
+    metrics: Arc<Metrics>,
+    thread_config: ThreadConfig,
     #[cfg(feature = "hdf")]
     write_caches: Vec<Box<dyn Cache>>,
 }
 
 impl Dumper {
-    pub fn new(passbands: &[Passband]) -> Self {
+    pub fn new(passbands: &[Passband], zero_points: &ZeroPoints) -> Self {
         Self {
             passbands: passbands.to_vec(),
+            zero_points: zero_points.clone(),
             dumps: vec![],
+            output_format: OutputFormat::Dat,
+            ordered: false,
+            failure_policy: FailurePolicy::Abort,
+            failure_path: None,
+            ch_sink: None,
+            checkpoint: None,
+            resume: None,
+            source_selection: None,
+            skipped_path: None,
+            quality_filter: None,
+            time_offset: TimeOffset::Fixed,
+            progress: None,
+            metrics: Default::default(),
+            thread_config: ThreadConfig::default(),
             #[cfg(feature = "hdf")]
             write_caches: vec![],
         }
@@ -224,38 +811,137 @@ impl Dumper {
         self
     }
 
+    /// Write MJD of per-source time offsets, they are used with `TimeOffset::Source` only
+    pub fn set_t0_writer(&mut self, t0_path: String) -> &mut Self {
+        self.dumps.push(Box::new(T0Dump { path: t0_path }));
+        self
+    }
+
     pub fn set_interpolator(
         &mut self,
         flux_path: String,
-        interpolator: Interpolator<f32, f32>,
+        json_path: String,
+        mjd_grid: Vec<f64>,
+        fill: InterpolationFill,
     ) -> &mut Self {
-        self.dumps.push(Box::new(FluxDump {
-            path: flux_path,
-            interpolator,
-            passbands: self.passbands.clone(),
-        }));
+        self.dumps.push(Box::new(FluxDump::new(
+            flux_path,
+            json_path,
+            mjd_grid,
+            fill,
+            self.passbands.clone(),
+            &self.zero_points,
+        )));
         self
     }
 
+    /// `flux_feature_extractors` are in the order of passbands
     pub fn set_feature_extractor(
         &mut self,
         value_path: String,
         name_path: String,
         json_path: String,
         magn_feature_extractor: Feature<f32>,
-        flux_feature_extractor: Feature<f32>,
+        flux_feature_extractors: Vec<Feature<f32>>,
+        color_features: Option<ColorFeatures>,
     ) -> &mut Self {
         self.dumps.push(Box::new(FeatureDump::new(
             value_path,
             name_path,
             json_path,
             magn_feature_extractor,
-            flux_feature_extractor,
+            flux_feature_extractors,
+            color_features,
             self.passbands.clone(),
+            &self.zero_points,
         )));
         self
     }
 
+    /// Format of value outputs, paths of the dumps must have corresponding extension
+    pub fn set_output_format(&mut self, output_format: OutputFormat) -> &mut Self {
+        self.output_format = output_format;
+        self
+    }
+
+    /// Write results in the order sources are received from the iterator
+    pub fn set_ordered(&mut self, ordered: bool) -> &mut Self {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Set what to do with sources which cannot be evaluated, their sids and error messages are
+    /// written to `failure_path`
+    pub fn set_failure_policy(
+        &mut self,
+        failure_policy: FailurePolicy,
+        failure_path: String,
+    ) -> &mut Self {
+        self.failure_policy = failure_policy;
+        self.failure_path = Some(failure_path);
+        self
+    }
+
+    /// Insert features into ClickHouse table in addition to the feature output
+    pub fn set_clickhouse_sink(&mut self, url: String, table: String) -> &mut Self {
+        self.ch_sink = Some((url, table));
+        self
+    }
+
+    /// Periodically save checkpoint, it requires ordered mode
+    pub fn set_checkpoint(&mut self, path: String, interval: Duration) -> &mut Self {
+        self.checkpoint = Some((path, interval));
+        self
+    }
+
+    /// Continue interrupted run: truncate outputs to the checkpoint and skip already written
+    /// sources, it requires ordered mode and the same source order as the interrupted run had
+    pub fn set_resume(&mut self, checkpoint: Checkpoint) -> &mut Self {
+        self.resume = Some(checkpoint);
+        self
+    }
+
+    /// Select observations by time and skip sources with too few observations, sids of skipped
+    /// sources and the reasons are written to `skipped_path` if it is specified
+    pub fn set_source_selection(
+        &mut self,
+        source_selection: SourceSelection,
+        skipped_path: Option<String>,
+    ) -> &mut Self {
+        self.source_selection = Some(source_selection);
+        self.skipped_path = skipped_path;
+        self
+    }
+
+    /// Filter observations before evaluation, cache is written with unfiltered sources
+    pub fn set_quality_filter(&mut self, quality_filter: QualityFilter) -> &mut Self {
+        self.quality_filter = Some(quality_filter);
+        self
+    }
+
+    /// Time offset of light curves evaluated by dumps
+    pub fn set_time_offset(&mut self, time_offset: TimeOffset) -> &mut Self {
+        self.time_offset = time_offset;
+        self
+    }
+
+    /// Print progress to stderr, `total` is the number of sources including the ones of the
+    /// resumed run, it is used to estimate remaining time
+    pub fn set_progress(&mut self, progress: ProgressConfig, total: Option<usize>) -> &mut Self {
+        self.progress = Some((progress, total));
+        self
+    }
+
+    pub fn set_thread_config(&mut self, thread_config: ThreadConfig) -> &mut Self {
+        self.thread_config = thread_config;
+        self
+    }
+
+    /// Metrics updated by the dumper threads
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
     #[cfg(feature = "hdf")]
     pub fn set_write_cache(&mut self, cache: Box<dyn Cache>) -> &mut Self {
         self.write_caches.push(cache);
@@ -267,29 +953,108 @@ impl Dumper {
         BufWriter::new(file)
     }
 
+    /// Returns numbers of observations and sources rejected by the source selection and
+    /// the quality filter
+    #[allow(clippy::too_many_arguments)]
     fn dump_eval_worker(
         dumps: Vec<Box<dyn Dump>>,
-        receiver: Receiver<Source>,
-        sender: Sender<Vec<Vec<u8>>>,
-    ) {
-        while let Ok(source) = receiver.recv() {
-            let results = dumps.iter().map(|dump| dump.eval(&source)).collect();
+        passbands: Vec<Passband>,
+        source_selection: Option<SourceSelection>,
+        quality_filter: Option<QualityFilter>,
+        time_offset: TimeOffset,
+        receiver: Receiver<(usize, Source)>,
+        sender: Sender<EvalMessage>,
+        metrics: Arc<Metrics>,
+    ) -> RejectionCounts {
+        let mut rejected = RejectionCounts::default();
+        while let Ok((seq, mut source)) = receiver.recv() {
+            let start = Instant::now();
+            let sid = source.sid;
+            // Panic is passed to the writer, otherwise it would wait for the lost result forever
+            let results: thread::Result<Result<Vec<_>, _>> =
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    if time_offset == TimeOffset::Fixed {
+                        source.set_time_offset(0.0);
+                    }
+                    if let Some(source_selection) = &source_selection {
+                        rejected.time_window += source_selection.apply_time_window(&mut source);
+                    }
+                    if let Some(quality_filter) = &quality_filter {
+                        rejected += quality_filter.apply(&mut source);
+                    }
+                    let selected = source_selection.as_ref().map_or(Ok(()), |selection| {
+                        selection.check_min_obs(&source, &passbands)
+                    });
+                    if selected.is_err() {
+                        rejected.skipped_sources += 1;
+                    }
+                    selected.map(|()| dumps.iter().map(|dump| dump.eval(&source)).collect())
+                }));
+            let panicked = results.is_err();
+            if let Ok(Ok(results)) = &results {
+                let errors: Vec<_> = results
+                    .iter()
+                    .flat_map(|result| result.errors.iter().cloned())
+                    .collect();
+                metrics.add_failures(&errors);
+            }
+            metrics.eval_latency.observe(start.elapsed());
             sender
-                .send(results)
+                .send((seq, sid, results))
                 .expect("Cannot send evaluation result to writer");
+            if panicked {
+                break;
+            }
+            metrics.counters.evaluated.fetch_add(1, Ordering::Relaxed);
         }
+        rejected
     }
 
-    fn dump_writer_worker(dumps: Vec<Box<dyn Dump>>, receiver: Receiver<Vec<Vec<u8>>>) {
-        let mut writers: Vec<_> = dumps
-            .iter()
-            .map(|dump| Self::writer_from_path(dump.get_value_path()))
-            .collect();
-        while let Ok(data) = receiver.recv() {
-            for (x, writer) in data.iter().zip(writers.iter_mut()) {
-                writer.write(&x[..]).expect("Cannot write to file");
+    /// Writes evaluation results, if `order_credits` is specified results are reordered by their
+    /// sequence numbers and a credit is returned for every written one. A panic of an eval worker
+    /// is resumed here, so the reader stops on the lost credits channel instead of waiting forever
+    fn dump_writer_worker(
+        mut writer: DumpWriter,
+        receiver: Receiver<EvalMessage>,
+        order_credits: Option<Sender<()>>,
+        metrics: Arc<Metrics>,
+    ) -> SourceCounts {
+        let write = |writer: &mut DumpWriter, sid, results| {
+            let start = Instant::now();
+            let counter = match &results {
+                Ok(_) => &metrics.counters.written,
+                Err(_) => &metrics.counters.skipped,
+            };
+            writer.write(sid, results);
+            metrics.write_latency.observe(start.elapsed());
+            counter.fetch_add(1, Ordering::Relaxed);
+        };
+        match order_credits {
+            Some(credits) => {
+                let mut buffer = ReorderBuffer::new();
+                while let Ok((seq, sid, results)) = receiver.recv() {
+                    let results = results.unwrap_or_else(|payload| panic::resume_unwind(payload));
+                    buffer.push(seq, (sid, results));
+                    while let Some((sid, results)) = buffer.pop() {
+                        write(&mut writer, sid, results);
+                        credits
+                            .send(())
+                            .expect("Cannot return order credit to reader");
+                    }
+                }
+                assert!(
+                    buffer.is_empty(),
+                    "Some evaluation results are lost, cannot restore source order"
+                );
+            }
+            None => {
+                while let Ok((_seq, sid, results)) = receiver.recv() {
+                    let results = results.unwrap_or_else(|payload| panic::resume_unwind(payload));
+                    write(&mut writer, sid, results);
+                }
             }
         }
+        writer.finish()
     }
 
     #[cfg(feature = "hdf")]
@@ -301,33 +1066,113 @@ impl Dumper {
         }
     }
 
-    pub fn dump_query_iter(&self, source_iter: impl Iterator<Item = Source>) {
-        const CHANNEL_CAP: usize = 1 << 10;
+    /// Evaluate and write sources, returns numbers of sources at every stage and numbers of
+    /// observations and sources rejected by the source selection and the quality filter
+    pub fn dump_query_iter(
+        &self,
+        mut source_iter: impl Iterator<Item = Source>,
+    ) -> (SourceCounts, RejectionCounts) {
+        let channel_cap = self.thread_config.channel_capacity;
+        let reorder_buffer_cap = self.thread_config.reorder_buffer_capacity;
 
-        let (dump_eval_sender, dump_eval_receiver) = bounded_channel(CHANNEL_CAP);
-        let (dump_writer_sender, dump_writer_receiver) = bounded_channel(CHANNEL_CAP);
+        let (dump_eval_sender, dump_eval_receiver) = bounded_channel(channel_cap);
+        let (dump_writer_sender, dump_writer_receiver) = bounded_channel(channel_cap);
         #[cfg(feature = "hdf")]
         let (cache_writer_senders, cache_writer_receivers): (Vec<_>, Vec<_>) = self
             .write_caches
             .iter()
-            .map(|_| bounded_channel(CHANNEL_CAP))
+            .map(|_| bounded_channel(channel_cap))
             .unzip();
 
-        let dump_eval_thread_pool: Vec<_> = (0..num_cpus::get())
-            .map(|_| {
+        let dump_eval_thread_pool: Vec<_> = (0..self.thread_config.eval_threads)
+            .map(|i| {
+                let cpu = self
+                    .thread_config
+                    .pin_cores
+                    .as_ref()
+                    .map(|cpus| cpus[i % cpus.len()]);
                 let dumps = self.dumps.clone();
+                let passbands = self.passbands.clone();
+                let source_selection = self.source_selection.clone();
+                let quality_filter = self.quality_filter.clone();
+                let time_offset = self.time_offset;
                 let receiver = dump_eval_receiver.clone();
                 let sender = dump_writer_sender.clone();
-                thread::spawn(move || Self::dump_eval_worker(dumps, receiver, sender))
+                let metrics = self.metrics.clone();
+                thread::spawn(move || {
+                    if let Some(cpu) = cpu {
+                        affinity::pin_current_thread(cpu);
+                    }
+                    Self::dump_eval_worker(
+                        dumps,
+                        passbands,
+                        source_selection,
+                        quality_filter,
+                        time_offset,
+                        receiver,
+                        sender,
+                        metrics,
+                    )
+                })
             })
             .collect();
         // Remove channel parts that are cloned and moved to workers
         drop(dump_eval_receiver);
         drop(dump_writer_sender);
 
-        let dumps = self.dumps.clone();
-        let dump_writer_thread =
-            thread::spawn(move || Self::dump_writer_worker(dumps, dump_writer_receiver));
+        // In ordered mode reader takes a credit for every source and writer returns it back when
+        // the source is written, so the reorder buffer cannot grow over its capacity
+        let (order_credit_sender, order_credit_receiver) = if self.ordered {
+            let (sender, receiver) = bounded_channel(reorder_buffer_cap);
+            for _ in 0..reorder_buffer_cap {
+                sender.send(()).unwrap();
+            }
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+
+        assert!(
+            self.ordered || (self.checkpoint.is_none() && self.resume.is_none()),
+            "Checkpoints require ordered mode"
+        );
+        let writer = DumpWriter::new(
+            &self.dumps,
+            self.output_format,
+            self.failure_policy,
+            self.failure_path.clone(),
+            self.skipped_path.clone(),
+            self.ch_sink.clone(),
+            self.checkpoint.clone(),
+            self.resume.as_ref(),
+        );
+        let resumed = self
+            .resume
+            .as_ref()
+            .map_or(0, |checkpoint| checkpoint.count);
+        if let Some(checkpoint) = &self.resume {
+            let last_source = source_iter.by_ref().take(checkpoint.count).last();
+            assert_eq!(
+                last_source.map(|source| source.sid),
+                checkpoint.sid,
+                "Sources differ from the ones of the interrupted run"
+            );
+        }
+        // Reporter stops when the stop sender is dropped
+        let (progress_stop_sender, progress_stop_receiver) = bounded_channel::<()>(0);
+        let progress_thread = self.progress.map(|(config, total)| {
+            let reporter = ProgressReporter::new(config, total, resumed, self.metrics.clone());
+            thread::spawn(move || reporter.run(progress_stop_receiver))
+        });
+        let writer_metrics = self.metrics.clone();
+        let dump_writer_thread = thread::spawn(move || {
+            Self::dump_writer_worker(
+                writer,
+                dump_writer_receiver,
+                order_credit_sender,
+                writer_metrics,
+            )
+        });
 
         #[cfg(feature = "hdf")]
         let cache_write_thread_pool: Vec<_> = self
@@ -340,33 +1185,103 @@ impl Dumper {
             })
             .collect();
 
-        for source in source_iter {
+        let counters = &self.metrics.counters;
+        // Read latency is the time to get the next source, waiting for workers is excluded
+        let mut read_start = Instant::now();
+        for (seq, source) in source_iter.enumerate() {
+            self.metrics.read_latency.observe(read_start.elapsed());
+            // Channels are disconnected if the writer or eval workers panicked, the panic is
+            // resumed when the writer is joined
+            if let Some(credits) = &order_credit_receiver {
+                if credits.recv().is_err() {
+                    break;
+                }
+            }
             #[cfg(feature = "hdf")]
             for sender in cache_writer_senders.iter() {
                 sender
                     .send(source.clone())
                     .expect("Cannot send task to cache worker");
             }
+            counters
+                .observations
+                .fetch_add(source.len(), Ordering::Relaxed);
             // Send source to eval worker pool
-            dump_eval_sender
-                .send(source)
-                .expect("Cannot send task to eval worker");
+            if dump_eval_sender.send((seq, source)).is_err() {
+                break;
+            }
+            counters.read.fetch_add(1, Ordering::Relaxed);
+            read_start = Instant::now();
         }
 
         // Remove senders or writer_thread will never join
         drop(dump_eval_sender);
         #[cfg(feature = "hdf")]
         drop(cache_writer_senders);
+        // Writer is joined first, because eval workers panic when it is stopped by a failed
+        // source, and this panic would hide the real cause
+        let counts = dump_writer_thread
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload));
+        let mut rejected = RejectionCounts::default();
         for thread in dump_eval_thread_pool {
-            thread.join().expect("Dumper eval worker panicked");
+            rejected += thread
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload));
         }
-        dump_writer_thread
-            .join()
-            .expect("Dumper writer worker panicked");
         #[cfg(feature = "hdf")]
         for thread in cache_write_thread_pool {
             thread.join().expect("Dumper cache writer worker panicked");
         }
+        drop(progress_stop_sender);
+        if let Some(thread) = progress_thread {
+            thread.join().expect("Progress reporter panicked");
+        }
+        (
+            SourceCounts {
+                resumed,
+                read: counters.read.load(Ordering::Relaxed),
+                ..counts
+            },
+            rejected,
+        )
+    }
+
+    /// All output files of the dumper, the HDF5 file shared by the dumps is listed once
+    pub fn output_files(&self) -> Vec<OutputFile> {
+        let mut files: Vec<OutputFile> = vec![];
+        for dump in self.dumps.iter() {
+            let array = OutputArray {
+                name: dump.get_value_name().to_owned(),
+                descr: NpyHeader::native(dump.get_value_dtype(), &[]).descr,
+                row_shape: dump.get_value_shape(),
+            };
+            match files
+                .iter_mut()
+                .find(|file| file.path == dump.get_value_path())
+            {
+                Some(file) => file.arrays.push(array),
+                None => files.push(OutputFile {
+                    path: dump.get_value_path().to_owned(),
+                    arrays: vec![array],
+                }),
+            }
+        }
+        let text_paths = self
+            .dumps
+            .iter()
+            .flat_map(|dump| [dump.get_name_path(), dump.get_json_path()])
+            .chain([
+                self.failure_path.as_deref(),
+                self.skipped_path.as_deref(),
+                self.checkpoint.as_ref().map(|(path, _)| path.as_str()),
+            ])
+            .flatten();
+        files.extend(text_paths.map(|path| OutputFile {
+            path: path.to_owned(),
+            arrays: vec![],
+        }));
+        files
     }
 
     pub fn write_names(&self) -> usize {
@@ -397,3 +1312,57 @@ impl Dumper {
             .sum()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reorder_buffer_restores_order() {
+        let mut buffer = ReorderBuffer::new();
+        let mut output = vec![];
+        for seq in [2, 0, 3, 1, 5, 4] {
+            buffer.push(seq, seq);
+            while let Some(x) = buffer.pop() {
+                output.push(x);
+            }
+        }
+        assert_eq!(output, vec![0, 1, 2, 3, 4, 5]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn reorder_buffer_repeated_seq_panic() {
+        let mut buffer = ReorderBuffer::new();
+        buffer.push(0, ());
+        buffer.pop();
+        buffer.push(0, ());
+    }
+
+    #[test]
+    fn eval_worker_panic_sent_to_writer() {
+        let (source_sender, source_receiver) = bounded_channel(2);
+        let (result_sender, result_receiver) = bounded_channel(2);
+        source_sender.send((0, Source::new(1))).unwrap();
+        source_sender.send((1, Source::new(2))).unwrap();
+        Dumper::dump_eval_worker(
+            vec![Box::new(PanicDump {
+                names: vec![],
+                shape: vec![],
+            })],
+            vec![],
+            None,
+            None,
+            TimeOffset::Source,
+            source_receiver,
+            result_sender,
+            Default::default(),
+        );
+        let (seq, sid, results): EvalMessage = result_receiver.recv().unwrap();
+        assert_eq!((seq, sid), (0, 1));
+        assert!(results.is_err());
+        // Worker stops after the panic, so the next source is not evaluated
+        assert!(result_receiver.recv().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::constants::{MAG_ZP_F32, MAG_ZP_F64};
+use crate::constants::MAG_ZP_F32;
 
 use light_curve_feature::transformers::{
     arcsinh::ArcsinhTransformer, bazin_fit::BazinFitTransformer, composed::ComposedTransformer,
@@ -11,6 +11,12 @@ pub enum FeatureVersion {
     Snad4,
     Snad6,
     SnadClf,
+    /// Extractors loaded from JSON having the same format as feature*.json output
+    Custom {
+        magn: Feature<f32>,
+        flux: Feature<f32>,
+        zero_point: f32,
+    },
 }
 
 impl FromStr for FeatureVersion {
@@ -27,6 +33,44 @@ impl FromStr for FeatureVersion {
 }
 
 impl FeatureVersion {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|err| format!("cannot parse feature JSON: {}", err))?;
+        let magn = serde_json::from_value(value["magn"].clone())
+            .map_err(|err| format!("cannot parse magn feature extractor: {}", err))?;
+        // Zero point was not written by older versions, it was always the default one
+        let zero_point = match &value["flux"]["zero_point"] {
+            serde_json::Value::Null => MAG_ZP_F32,
+            serde_json::Value::Number(zero_point) => zero_point.as_f64().unwrap() as f32,
+            _ => return Err("flux extractor must have a single zero point".to_owned()),
+        };
+        let flux = serde_json::from_value(value["flux"]["extractor"].clone())
+            .map_err(|err| format!("cannot parse flux feature extractor: {}", err))?;
+        Ok(Self::Custom {
+            magn,
+            flux,
+            zero_point,
+        })
+    }
+
+    /// Name accepted by --feature-version, or "custom" for extractors loaded from JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Snad4 => "snad4",
+            Self::Snad6 => "snad6",
+            Self::SnadClf => "snad_clf",
+            Self::Custom { .. } => "custom",
+        }
+    }
+
+    /// Zero point the flux extractor is bound to, it is None for versions supporting any
+    pub fn zero_point(&self) -> Option<f32> {
+        match self {
+            Self::Custom { zero_point, .. } => Some(*zero_point),
+            _ => None,
+        }
+    }
+
     fn snad4_magn_extractor() -> Feature<f32> {
         let mut periodogram_feature_evaluator = Periodogram::new(5);
         periodogram_feature_evaluator.set_nyquist(NyquistFreq::fixed(24.0));
@@ -86,7 +130,6 @@ impl FeatureVersion {
             Transformed::new(feature, transformer).unwrap().into()
         };
 
-
         let linear_fit: Feature<f32> = {
             let feature: Feature<f32> = LinearFit::default().into();
             let transformer: Transformer<f32> = ComposedTransformer::new(vec![
@@ -126,7 +169,6 @@ impl FeatureVersion {
             Transformed::new(feature, transformer).unwrap().into()
         };
 
-
         let reduced_chi2: Feature<f32> = {
             let feature = ReducedChi2::new().into();
             let transformer: Transformer<f32> = Ln1pTransformer {}.into();
@@ -152,8 +194,6 @@ impl FeatureVersion {
             Transformed::new(feature, transformer).unwrap().into()
         };
 
-
-
         FeatureExtractor::from_features(vec![
             Amplitude::default().into(),
             anderson_darling_normal,
@@ -179,9 +219,6 @@ impl FeatureVersion {
         .into()
     }
 
-
-
-
     fn snad6_magn_extractor() -> Feature<f32> {
         let bins: Feature<f32> = {
             let eta_e: Feature<f32> = {
@@ -299,7 +336,7 @@ impl FeatureVersion {
             Self::Snad4 => Self::snad4_magn_extractor(),
             Self::Snad6 => Self::snad6_magn_extractor(),
             Self::SnadClf => Self::snad_clf_magn_extractor(),
-
+            Self::Custom { magn, .. } => magn.clone(),
         }
     }
 
@@ -318,23 +355,23 @@ impl FeatureVersion {
         .into()
     }
 
-    fn snad_clf_flux_extractor() -> Feature<f32> {
+    fn snad_clf_flux_extractor(zero_point: f32) -> Feature<f32> {
         let bazin_fit: Feature<f32> = {
             let inits_bounds = BazinInitsBounds::option_arrays(
                 [None; 5],
                 [
-                    Some(f64::powf(10.0, -0.4 * (30.0 - MAG_ZP_F64))), // amplitude
-                    None,                                              // baseline
-                    None,                                              // t0
-                    Some(1e-4),                                        // rise time
-                    Some(1e-4),                                        // fall time
+                    Some(f64::powf(10.0, -0.4 * (30.0 - zero_point as f64))), // amplitude
+                    None,                                                     // baseline
+                    None,                                                     // t0
+                    Some(1e-4),                                               // rise time
+                    Some(1e-4),                                               // fall time
                 ],
                 [
-                    Some(f64::powf(10.0, -0.4 * (0.0 - MAG_ZP_F64))), // amplitude
-                    None,                                             // baseline
-                    None,                                             // t0
-                    Some(3e4),                                        // rise time
-                    Some(3e4),                                        // fall time
+                    Some(f64::powf(10.0, -0.4 * (0.0 - zero_point as f64))), // amplitude
+                    None,                                                    // baseline
+                    None,                                                    // t0
+                    Some(3e4),                                               // rise time
+                    Some(3e4),                                               // fall time
                 ],
             );
 
@@ -344,7 +381,7 @@ impl FeatureVersion {
                 inits_bounds,
             );
             let feature: Feature<f32> = fit.into();
-            let transformer = Transformer::BazinFit(BazinFitTransformer::new(MAG_ZP_F32));
+            let transformer = Transformer::BazinFit(BazinFitTransformer::new(zero_point));
             let transformed = Transformed::new(feature, transformer).unwrap();
             transformed.into()
         };
@@ -357,9 +394,7 @@ impl FeatureVersion {
         .into()
     }
 
-
-
-    fn snad6_flux_extractor() -> Feature<f32> {
+    fn snad6_flux_extractor(zero_point: f32) -> Feature<f32> {
         let anderson_darling_normal: Feature<f32> = {
             let feature = AndersonDarlingNormal::default().into();
             let transformer: Transformer<f32> = Ln1pTransformer {}.into();
@@ -370,18 +405,18 @@ impl FeatureVersion {
             let inits_bounds = BazinInitsBounds::option_arrays(
                 [None; 5],
                 [
-                    Some(f64::powf(10.0, -0.4 * (30.0 - MAG_ZP_F64))), // amplitude
-                    None,                                              // baseline
-                    None,                                              // t0
-                    Some(1e-4),                                        // rise time
-                    Some(1e-4),                                        // fall time
+                    Some(f64::powf(10.0, -0.4 * (30.0 - zero_point as f64))), // amplitude
+                    None,                                                     // baseline
+                    None,                                                     // t0
+                    Some(1e-4),                                               // rise time
+                    Some(1e-4),                                               // fall time
                 ],
                 [
-                    Some(f64::powf(10.0, -0.4 * (0.0 - MAG_ZP_F64))), // amplitude
-                    None,                                             // baseline
-                    None,                                             // t0
-                    Some(3e4),                                        // rise time
-                    Some(3e4),                                        // fall time
+                    Some(f64::powf(10.0, -0.4 * (0.0 - zero_point as f64))), // amplitude
+                    None,                                                    // baseline
+                    None,                                                    // t0
+                    Some(3e4),                                               // rise time
+                    Some(3e4),                                               // fall time
                 ],
             );
 
@@ -391,7 +426,7 @@ impl FeatureVersion {
                 inits_bounds,
             );
             let feature: Feature<f32> = fit.into();
-            let transformer = Transformer::BazinFit(BazinFitTransformer::new(MAG_ZP_F32));
+            let transformer = Transformer::BazinFit(BazinFitTransformer::new(zero_point));
             let transformed = Transformed::new(feature, transformer).unwrap();
             transformed.into()
         };
@@ -404,12 +439,13 @@ impl FeatureVersion {
         .into()
     }
 
-    pub fn flux_extractor(&self) -> Feature<f32> {
+    /// Flux extractor for fluxes having the zero point, it is ignored by custom extractor
+    pub fn flux_extractor(&self, zero_point: f32) -> Feature<f32> {
         match self {
             Self::Snad4 => Self::snad4_flux_extractor(),
-            Self::Snad6 => Self::snad6_flux_extractor(),
-            Self::SnadClf => Self::snad_clf_flux_extractor(),
-
+            Self::Snad6 => Self::snad6_flux_extractor(zero_point),
+            Self::SnadClf => Self::snad_clf_flux_extractor(zero_point),
+            Self::Custom { flux, .. } => flux.clone(),
         }
     }
 }
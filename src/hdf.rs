@@ -1,7 +1,8 @@
 use crate::lc::{Observation, Source};
-use crate::traits::{Cache, CacheWriter, ObservationsToSources};
+use crate::traits::{Cache, CacheWriter, Dump, ObservationsToSources};
 
-use hdf5::Dataset;
+use hdf5::types::VarLenUnicode;
+use hdf5::{Dataset, H5Type};
 use light_curve_feature::ndarray;
 
 const DATASET_SIZE_STEP: hdf5::Ix = 1 << 16;
@@ -132,3 +133,211 @@ impl Drop for Hdf5CacheWriter {
         self.dataset.resize(self.index).unwrap();
     }
 }
+
+/// Number of rows buffered before they are written to output dataset
+const OUTPUT_BUFFER_ROWS: usize = 1 << 10;
+
+/// Writes dump values to a resizable dataset of HDF5 file, the dataset is named after values
+///
+/// Dataset has a row per source and attributes of value names, dump JSON and MJD grid if the dump
+/// has them
+pub struct Hdf5ValueWriter {
+    dataset: Dataset,
+    dtype: String,
+    row_len: usize,
+    buffer: Vec<u8>,
+    index: usize,
+    size: usize,
+}
+
+impl Hdf5ValueWriter {
+    pub fn new(file: &hdf5::File, dump: &dyn Dump) -> Self {
+        let dtype = dump.get_value_dtype().to_owned();
+        let dataset = match dtype.as_str() {
+            "u8" => Self::dataset::<u64>(file, dump),
+            "f4" => Self::dataset::<f32>(file, dump),
+            "f8" => Self::dataset::<f64>(file, dump),
+            _ => panic!("values of type {} cannot be written to HDF5", dtype),
+        }
+        .expect("Cannot create output dataset");
+        Self::write_attributes(&dataset, dump).expect("Cannot write output dataset attributes");
+        let size = DATASET_SIZE_STEP;
+        Self {
+            dataset,
+            dtype,
+            row_len: dump.get_value_shape().iter().product(),
+            buffer: vec![],
+            index: 0,
+            size,
+        }
+    }
+
+    fn dataset<T: H5Type>(file: &hdf5::File, dump: &dyn Dump) -> hdf5::Result<Dataset> {
+        let builder = file.new_dataset::<T>();
+        match dump.get_value_shape()[..] {
+            [] => builder
+                .shape((DATASET_SIZE_STEP..,))
+                .chunk((OUTPUT_BUFFER_ROWS,))
+                .create(dump.get_value_name()),
+            [row_len] => builder
+                .shape((DATASET_SIZE_STEP.., row_len))
+                .chunk((OUTPUT_BUFFER_ROWS, row_len))
+                .create(dump.get_value_name()),
+            _ => panic!("only scalar and 1-D values can be written to HDF5"),
+        }
+    }
+
+    fn write_attributes(dataset: &Dataset, dump: &dyn Dump) -> hdf5::Result<()> {
+        let names: Vec<VarLenUnicode> = dump
+            .get_names()
+            .iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        if !names.is_empty() {
+            dataset
+                .new_attr::<VarLenUnicode>()
+                .shape(names.len())
+                .create("names")?
+                .write(&names)?;
+        }
+        if !dump.get_json().is_empty() {
+            let json: VarLenUnicode = dump.get_json().parse().unwrap();
+            dataset
+                .new_attr::<VarLenUnicode>()
+                .shape(())
+                .create("json")?
+                .write_scalar(&json)?;
+        }
+        if let Some(grid) = dump.get_value_grid() {
+            dataset
+                .new_attr::<f64>()
+                .shape(grid.len())
+                .create("mjd")?
+                .write(grid)?;
+        }
+        Ok(())
+    }
+
+    /// `data` is native-endian values of a single source
+    pub fn write(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= OUTPUT_BUFFER_ROWS * self.row_bytes() {
+            self.flush();
+        }
+    }
+
+    fn row_bytes(&self) -> usize {
+        let item_size: usize = self.dtype[1..].parse().unwrap();
+        item_size * self.row_len
+    }
+
+    /// Write buffered rows to the dataset
+    pub fn flush(&mut self) {
+        let rows = self.buffer.len() / self.row_bytes();
+        if rows == 0 {
+            return;
+        }
+
+        let begin = self.index;
+        self.index += rows;
+        if self.index >= self.size {
+            while self.size <= self.index {
+                self.size += DATASET_SIZE_STEP;
+            }
+            self.resize(self.size);
+        }
+
+        let buffer = std::mem::take(&mut self.buffer);
+        match self.dtype.as_str() {
+            "u8" => self.write_slice(begin, Self::values(&buffer, u64::from_ne_bytes)),
+            "f4" => self.write_slice(begin, Self::values(&buffer, f32::from_ne_bytes)),
+            "f8" => self.write_slice(begin, Self::values(&buffer, f64::from_ne_bytes)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn values<T, const N: usize>(buffer: &[u8], from_bytes: fn([u8; N]) -> T) -> Vec<T> {
+        buffer
+            .chunks_exact(N)
+            .map(|bytes| from_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    fn write_slice<T: H5Type>(&self, begin: usize, values: Vec<T>) {
+        let end = self.index;
+        if self.dataset.ndim() == 1 {
+            let selection: hdf5::Selection = ndarray::s![begin..end].try_into().unwrap();
+            self.dataset.write_slice(&values, &selection).unwrap();
+        } else {
+            let values = ndarray::Array2::from_shape_vec((end - begin, self.row_len), values)
+                .expect("Unexpected number of values");
+            let selection: hdf5::Selection = ndarray::s![begin..end, ..].try_into().unwrap();
+            self.dataset.write_slice(&values, &selection).unwrap();
+        }
+    }
+
+    /// Write buffered rows and shrink the dataset to the written ones
+    pub fn close(mut self) {
+        self.flush();
+        self.resize(self.index);
+    }
+
+    fn resize(&self, size: usize) {
+        if self.dataset.ndim() == 1 {
+            self.dataset.resize(size).unwrap();
+        } else {
+            self.dataset.resize((size, self.row_len)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::traits::PanicDump;
+
+    #[test]
+    fn hdf5_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("feat_extr_hdf5_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.hdf5");
+
+        // File is closed at the end of the block
+        {
+            let file = hdf5::File::create(&path).unwrap();
+            let dump = PanicDump {
+                names: vec!["a", "b"],
+                shape: vec![2],
+            };
+            let mut writer = Hdf5ValueWriter::new(&file, &dump);
+            for sid in 0..3 {
+                let data: Vec<u8> = [sid as f32, -(sid as f32)]
+                    .iter()
+                    .flat_map(|x| x.to_ne_bytes())
+                    .collect();
+                writer.write(&data);
+            }
+            writer.close();
+        }
+
+        let file = hdf5::File::open(&path).unwrap();
+        let dataset = file.dataset("features").unwrap();
+        assert_eq!(dataset.shape(), vec![3, 2]);
+        let values = dataset.read_2d::<f32>().unwrap();
+        assert_eq!(values.column(1).to_vec(), vec![0.0, -1.0, -2.0]);
+        let names = dataset
+            .attr("names")
+            .unwrap()
+            .read_1d::<VarLenUnicode>()
+            .unwrap();
+        assert_eq!(
+            names.iter().map(|name| name.as_str()).collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        let json: VarLenUnicode = dataset.attr("json").unwrap().read_scalar().unwrap();
+        assert_eq!(json.as_str(), "{}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
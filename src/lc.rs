@@ -1,132 +1,446 @@
+use crate::constants::MAG_ZP_F32;
+
 #[cfg(feature = "hdf")]
 use hdf5::H5Type;
 
 use light_curve_common::sort_multiple;
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::str::FromStr;
 
 pub const MJD0: f64 = 58000.0;
 
-#[derive(FromPrimitive, Copy, Clone)]
-#[cfg_attr(feature = "hdf", derive(H5Type))]
-#[repr(u8)]
-pub enum Passband {
-    G = 1,
-    R = 2,
-    I = 3,
+pub fn mag_to_flux(mag: f32, zero_point: f32) -> f32 {
+    10_f32.powf(-0.4 * (mag - zero_point))
+}
+
+/// Magnitude of the flux, it is NaN for non-positive flux
+pub fn flux_to_mag(flux: f32, zero_point: f32) -> f32 {
+    if flux > 0.0 {
+        zero_point - 2.5 * flux.log10()
+    } else {
+        f32::NAN
+    }
+}
+
+/// Ratio of flux weight to magnitude weight, i.e. squared derivative of magnitude by flux
+pub fn flux_to_mag_weight_ratio(flux: f32) -> f32 {
+    f32::powi(0.4 * f32::ln(10.0) * flux, -2)
+}
+
+/// Inverse squared error, it is NaN for NaN error and infinite for zero error, bad errors are
+/// dropped by `QualityFilter` if it is asked to
+fn weight(err: f32) -> f32 {
+    err.powi(-2)
+}
+
+/// Brightness columns of the input: magnitude and its error, or flux and its error
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Brightness {
+    #[default]
+    Magnitude,
+    Flux,
+}
+
+impl Brightness {
+    /// Default names of brightness and its error columns
+    pub fn columns(&self) -> [&'static str; 2] {
+        match self {
+            Self::Magnitude => ["mag", "magerr"],
+            Self::Flux => ["flux", "fluxerr"],
+        }
+    }
 }
 
-impl Passband {
-    pub const fn n_filters() -> usize {
-        3
+impl FromStr for Brightness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mag" => Ok(Self::Magnitude),
+            "flux" => Ok(Self::Flux),
+            _ => Err(format!("unknown brightness type: {}", s)),
+        }
     }
+}
+
+/// Magnitude zero points, i.e. magnitudes of unit flux, by passband code
+///
+/// It is parsed from a string like "23.9,g=26.3", the value without a passband name is used for
+/// all other passbands
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZeroPoints {
+    pub default: f32,
+    pub passbands: BTreeMap<u8, f32>,
+}
 
-    fn lcs_index(self) -> usize {
-        self as usize - 1
+impl ZeroPoints {
+    pub fn new(default: f32) -> Self {
+        Self {
+            default,
+            passbands: BTreeMap::new(),
+        }
     }
 
-    pub fn from_lcs_index(i: usize) -> Self {
-        FromPrimitive::from_usize(i + 1).expect("lcs index should is 0, 1 or 2")
+    pub fn parse(registry: &PassbandRegistry, s: &str) -> Result<Self, String> {
+        let mut zero_points = Self::default();
+        for item in s.split(',') {
+            let (code, value) = match item.split_once('=') {
+                Some((name, value)) => {
+                    let passband = registry
+                        .find(name.trim())
+                        .ok_or_else(|| format!("passband {} of zero point is unknown", name))?;
+                    (Some(passband.code), value)
+                }
+                None => (None, item),
+            };
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("zero point {} must be a number", value))?;
+            match code {
+                Some(code) => {
+                    zero_points.passbands.insert(code, value);
+                }
+                None => zero_points.default = value,
+            }
+        }
+        Ok(zero_points)
     }
 
-    pub fn code(self) -> u8 {
-        self as u8
+    pub fn get(&self, code: u8) -> f32 {
+        self.passbands.get(&code).copied().unwrap_or(self.default)
     }
 
-    pub fn from_code(code: u8) -> Self {
-        FromPrimitive::from_u8(code).expect("code should be 1, 2 or 3")
+    /// Single zero point if it is the same for all the passbands
+    pub fn uniform(&self, passbands: &[Passband]) -> Option<f32> {
+        let zero_point = self.get(passbands.first()?.code);
+        passbands
+            .iter()
+            .all(|passband| self.get(passband.code) == zero_point)
+            .then_some(zero_point)
     }
+
+    /// Number if zero point is the same for all the passbands, or object of passband zero points
+    pub fn json(&self, passbands: &[Passband]) -> serde_json::Value {
+        match self.uniform(passbands) {
+            Some(zero_point) => zero_point.into(),
+            None => passbands
+                .iter()
+                .map(|passband| (passband.to_string(), self.get(passband.code).into()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+}
+
+/// Zero point of fluxes in μJy
+impl Default for ZeroPoints {
+    fn default() -> Self {
+        Self::new(MAG_ZP_F32)
+    }
+}
+
+/// Brightness type of the input and zero points used to convert it to the other type
+#[derive(Clone, Debug, Default)]
+pub struct Photometry {
+    pub brightness: Brightness,
+    pub zero_points: ZeroPoints,
+}
+
+impl Photometry {
+    /// Brightness type and zero points, zero points are in the same format as in feature JSON
+    pub fn json(&self, passbands: &[Passband]) -> serde_json::Value {
+        let brightness = match self.brightness {
+            Brightness::Magnitude => "mag",
+            Brightness::Flux => "flux",
+        };
+        serde_json::json!({
+            "brightness": brightness,
+            "zero_point": self.zero_points.json(passbands),
+        })
+    }
+
+    /// Construct observation from DB-like values: MJD time, brightness and its error
+    pub fn observation(&self, sid: u64, filter: u8, mjd: f64, value: f32, err: f32) -> Observation {
+        let zero_point = self.zero_points.get(filter);
+        match self.brightness {
+            Brightness::Magnitude => {
+                Observation::from_mjd_magerr(sid, filter, mjd, value, err, zero_point)
+            }
+            Brightness::Flux => {
+                Observation::from_mjd_fluxerr(sid, filter, mjd, value, err, zero_point)
+            }
+        }
+    }
+}
+
+/// Passband name and its numeric code used by DB
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Passband {
+    pub name: String,
+    pub code: u8,
 }
 
 impl fmt::Display for Passband {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = match self {
-            Passband::G => "g",
-            Passband::R => "r",
-            Passband::I => "i",
-        };
-        write!(f, "{}", s)
+        write!(f, "{}", self.name)
     }
 }
 
-impl From<String> for Passband {
-    fn from(item: String) -> Self {
-        match item.as_str() {
-            "g" => Passband::G,
-            "r" => Passband::R,
-            "i" => Passband::I,
-            _ => panic!("passband {} is unknown", item),
+/// Set of known passbands, it is parsed from a string like "g=1,r=2,i=3"
+#[derive(Clone, Debug)]
+pub struct PassbandRegistry {
+    passbands: Vec<Passband>,
+}
+
+impl PassbandRegistry {
+    pub fn get(&self, name: &str) -> Passband {
+        self.find(name)
+            .unwrap_or_else(|| panic!("passband {} is unknown", name))
+    }
+
+    pub fn find(&self, name: &str) -> Option<Passband> {
+        self.passbands
+            .iter()
+            .find(|passband| passband.name == name)
+            .cloned()
+    }
+
+    /// Passbands from a string of names, like "BP,RP" or "gr" for single-letter names
+    pub fn parse_names(&self, names: &str) -> Vec<Passband> {
+        if names.contains(',') {
+            names.split(',').map(|name| self.get(name.trim())).collect()
+        } else {
+            names.chars().map(|c| self.get(&c.to_string())).collect()
+        }
+    }
+}
+
+/// ZTF passbands: g=1, r=2, i=3
+impl Default for PassbandRegistry {
+    fn default() -> Self {
+        "g=1,r=2,i=3".parse().unwrap()
+    }
+}
+
+impl FromStr for PassbandRegistry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let passbands: Vec<_> = s
+            .split(',')
+            .map(|item| {
+                let (name, code) = item
+                    .split_once('=')
+                    .ok_or_else(|| format!("passband {} must be specified as name=code", item))?;
+                let code = code
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("passband code {} must be in 0..=255", code))?;
+                Ok(Passband {
+                    name: name.trim().to_owned(),
+                    code,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        for (i, passband) in passbands.iter().enumerate() {
+            if passbands[..i]
+                .iter()
+                .any(|other| other.name == passband.name || other.code == passband.code)
+            {
+                return Err(format!("passband {} is specified twice", passband));
+            }
         }
+        Ok(Self { passbands })
     }
 }
 
+static EMPTY_LIGHT_CURVE: LightCurve = LightCurve {
+    t: Vec::new(),
+    mag: Vec::new(),
+    w: Vec::new(),
+    flux: Vec::new(),
+    flux_w: Vec::new(),
+};
+
+/// Source light curves by passband code
 #[derive(Clone)]
 pub struct Source {
     pub sid: u64,
-    pub lcs: [LightCurve; Passband::n_filters()],
+    /// Time offset of light curves relative to MJD0, days. It is the integer part of the time of
+    /// the first pushed observation having finite time, so f32 light curve times keep the full
+    /// precision of observation times for any epoch. It is zero if there is no such observation
+    pub t0: f64,
+    pub lcs: BTreeMap<u8, LightCurve>,
 }
 
 impl Source {
-    pub fn lc(&self, passband: Passband) -> &LightCurve {
-        &self.lcs[passband.lcs_index()]
+    pub fn new(sid: u64) -> Self {
+        Self {
+            sid,
+            t0: 0.0,
+            lcs: Default::default(),
+        }
     }
 
-    pub fn lc_mut(&mut self, passband: Passband) -> &mut LightCurve {
-        &mut self.lcs[passband.lcs_index()]
+    /// Light curve of the passband, it is empty if source has no observations in the passband
+    pub fn lc(&self, passband: &Passband) -> &LightCurve {
+        self.lcs.get(&passband.code).unwrap_or(&EMPTY_LIGHT_CURVE)
+    }
+
+    pub fn lc_mut(&mut self, code: u8) -> &mut LightCurve {
+        self.lcs.entry(code).or_default()
     }
 
     pub fn push_observation(&mut self, obs: &Observation) {
-        self.lc_mut(obs.passband).push_observation(obs)
+        // Non-finite times stay non-finite for any offset, so they are pushed before the offset
+        // is known
+        if obs.t.is_finite() && !self.has_finite_time() {
+            self.t0 = obs.t.floor();
+        }
+        let t0 = self.t0;
+        self.lc_mut(obs.passband).push_observation(obs, t0)
+    }
+
+    fn has_finite_time(&self) -> bool {
+        self.lcs
+            .values()
+            .any(|lc| lc.t.iter().any(|t| t.is_finite()))
+    }
+
+    /// Change time offset shifting light curve times accordingly
+    pub fn set_time_offset(&mut self, t0: f64) {
+        assert!(t0.is_finite(), "time offset must be finite, not {}", t0);
+        if t0 == self.t0 {
+            return;
+        }
+        let shift = self.t0 - t0;
+        for lc in self.lcs.values_mut() {
+            lc.t.iter_mut()
+                .for_each(|t| *t = (*t as f64 + shift) as f32);
+        }
+        self.t0 = t0;
+    }
+
+    /// Append light curves of the other source having the same sid
+    pub fn append(&mut self, mut other: Source) {
+        assert_eq!(
+            self.sid, other.sid,
+            "cannot append source with different sid"
+        );
+        other.set_time_offset(self.t0);
+        for (code, lc) in other.lcs {
+            self.lc_mut(code).append(lc);
+        }
     }
 
     pub fn sort(&mut self) {
-        for lc in self.lcs.iter_mut() {
+        for lc in self.lcs.values_mut() {
             lc.sort();
         }
     }
 
     pub fn len(&self) -> usize {
-        self.lcs.iter().map(|lc| lc.t.len()).sum()
+        self.lcs.values().map(|lc| lc.t.len()).sum()
     }
 
     pub fn iter_observations(&self) -> impl Iterator<Item = Observation> + '_ {
-        self.lcs.iter().enumerate().flat_map(move |(i, lc)| {
-            let passband = Passband::from_lcs_index(i);
-            lc.t.iter()
-                .zip(lc.mag.iter())
-                .zip(lc.w.iter())
-                .map(move |((&t, &mag), &w)| Observation {
-                    sid: self.sid,
-                    t,
-                    mag,
-                    w,
-                    passband,
-                })
+        self.lcs.iter().flat_map(move |(&passband, lc)| {
+            (0..lc.t.len()).map(move |i| Observation {
+                sid: self.sid,
+                t: self.t0 + lc.t[i] as f64,
+                mag: lc.mag[i],
+                w: lc.w[i],
+                flux: lc.flux[i],
+                flux_w: lc.flux_w[i],
+                passband,
+            })
         })
     }
 }
 
-#[derive(Clone)]
+/// Light curve in both magnitudes and fluxes, one of them is native for the input and another
+/// one is derived from it
+#[derive(Clone, Default)]
 pub struct LightCurve {
     pub t: Vec<f32>,
     pub mag: Vec<f32>,
     pub w: Vec<f32>,
+    pub flux: Vec<f32>,
+    pub flux_w: Vec<f32>,
 }
 
 impl LightCurve {
-    pub fn push_observation(&mut self, obs: &Observation) {
-        self.t.push(obs.t);
+    /// `t0` is the time offset of the light curve
+    pub fn push_observation(&mut self, obs: &Observation, t0: f64) {
+        self.t.push((obs.t - t0) as f32);
         self.mag.push(obs.mag);
         self.w.push(obs.w);
+        self.flux.push(obs.flux);
+        self.flux_w.push(obs.flux_w);
+    }
+
+    pub fn append(&mut self, other: LightCurve) {
+        self.t.extend(other.t);
+        self.mag.extend(other.mag);
+        self.w.extend(other.w);
+        self.flux.extend(other.flux);
+        self.flux_w.extend(other.flux_w);
     }
 
     pub fn sort(&mut self) {
-        let mut tmw = sort_multiple(&[&self.t, &self.mag, &self.w]);
-        self.w = tmw.pop().unwrap();
-        self.mag = tmw.pop().unwrap();
-        self.t = tmw.pop().unwrap();
-        assert!(tmw.is_empty());
+        let mut columns = sort_multiple(&[&self.t, &self.mag, &self.w, &self.flux, &self.flux_w]);
+        self.flux_w = columns.pop().unwrap();
+        self.flux = columns.pop().unwrap();
+        self.w = columns.pop().unwrap();
+        self.mag = columns.pop().unwrap();
+        self.t = columns.pop().unwrap();
+        assert!(columns.is_empty());
+    }
+
+    /// Keep observations satisfying the predicate of the light curve and observation index,
+    /// returns number of removed observations
+    pub fn retain(&mut self, mut predicate: impl FnMut(&Self, usize) -> bool) -> usize {
+        let len = self.t.len();
+        let mut j = 0;
+        for i in 0..len {
+            if predicate(self, i) {
+                self.move_observation(i, j);
+                j += 1;
+            }
+        }
+        self.truncate(j);
+        len - j
+    }
+
+    /// Light curve without observations of undefined magnitude, which is NaN for non-positive
+    /// native flux
+    pub fn magn(&self) -> Cow<'_, Self> {
+        if self.mag.iter().all(|m| !m.is_nan()) {
+            return Cow::Borrowed(self);
+        }
+        let mut lc = self.clone();
+        lc.retain(|lc, i| !lc.mag[i].is_nan());
+        Cow::Owned(lc)
+    }
+
+    /// Copy observation from index `from` to index `to`
+    pub fn move_observation(&mut self, from: usize, to: usize) {
+        self.t[to] = self.t[from];
+        self.mag[to] = self.mag[from];
+        self.w[to] = self.w[from];
+        self.flux[to] = self.flux[from];
+        self.flux_w[to] = self.flux_w[from];
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.t.truncate(len);
+        self.mag.truncate(len);
+        self.w.truncate(len);
+        self.flux.truncate(len);
+        self.flux_w.truncate(len);
     }
 }
 
@@ -135,10 +449,65 @@ impl LightCurve {
 #[repr(C)]
 pub struct Observation {
     pub sid: u64,
-    pub t: f32,
+    /// Time relative to MJD0, days
+    pub t: f64,
     pub mag: f32,
     pub w: f32,
-    pub passband: Passband,
+    pub flux: f32,
+    pub flux_w: f32,
+    /// Passband code
+    pub passband: u8,
+}
+
+impl Observation {
+    /// Construct observation from DB-like values: MJD time and magnitude error
+    pub fn from_mjd_magerr(
+        sid: u64,
+        filter: u8,
+        mjd: f64,
+        mag: f32,
+        magerr: f32,
+        zero_point: f32,
+    ) -> Self {
+        let w = weight(magerr);
+        let flux = mag_to_flux(mag, zero_point);
+        Self {
+            sid,
+            passband: filter,
+            t: mjd - MJD0,
+            mag,
+            w,
+            flux,
+            flux_w: w * flux_to_mag_weight_ratio(flux),
+        }
+    }
+
+    /// Construct observation from DB-like values: MJD time and flux error. Magnitude and its
+    /// weight are NaN for non-positive flux
+    pub fn from_mjd_fluxerr(
+        sid: u64,
+        filter: u8,
+        mjd: f64,
+        flux: f32,
+        fluxerr: f32,
+        zero_point: f32,
+    ) -> Self {
+        let flux_w = weight(fluxerr);
+        let mag = flux_to_mag(flux, zero_point);
+        Self {
+            sid,
+            passband: filter,
+            t: mjd - MJD0,
+            mag,
+            w: if mag.is_nan() {
+                f32::NAN
+            } else {
+                flux_w / flux_to_mag_weight_ratio(flux)
+            },
+            flux,
+            flux_w,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -146,16 +515,115 @@ mod tests {
     use super::*;
 
     #[test]
-    fn passband_from_lcs_indix_valid() {
-        for i in 0..Passband::n_filters() {
-            let _passband = Passband::from_lcs_index(i);
+    fn passband_registry_from_str() {
+        let registry: PassbandRegistry = "u=0, g=1, r=2, i=3, z=4, y=5".parse().unwrap();
+        assert_eq!(registry.get("u").code, 0);
+        assert_eq!(registry.get("z").code, 4);
+    }
+
+    #[test]
+    fn passband_registry_from_str_invalid() {
+        assert!("g=1,r".parse::<PassbandRegistry>().is_err());
+        assert!("g=1,r=256".parse::<PassbandRegistry>().is_err());
+        assert!("g=1,g=2".parse::<PassbandRegistry>().is_err());
+        assert!("g=1,r=1".parse::<PassbandRegistry>().is_err());
+    }
+
+    #[test]
+    fn passband_registry_parse_names() {
+        let registry: PassbandRegistry = "BP=1,RP=2,G=3".parse().unwrap();
+        let passbands = registry.parse_names("G,BP");
+        assert_eq!(passbands[0].code, 3);
+        assert_eq!(passbands[1].code, 1);
+
+        let passbands = PassbandRegistry::default().parse_names("ri");
+        assert_eq!(passbands[0].code, 2);
+        assert_eq!(passbands[1].code, 3);
+    }
+
+    #[test]
+    fn observation_from_flux() {
+        let from_mag = Observation::from_mjd_magerr(0, 1, MJD0, 15.0, 0.1, MAG_ZP_F32);
+        let from_flux = Observation::from_mjd_fluxerr(
+            0,
+            1,
+            MJD0,
+            from_mag.flux,
+            0.1 * 0.4 * f32::ln(10.0) * from_mag.flux,
+            MAG_ZP_F32,
+        );
+        assert!((from_flux.mag - 15.0).abs() < 1e-5);
+        assert!((from_flux.w / from_mag.w - 1.0).abs() < 1e-5);
+        assert!((from_flux.flux_w / from_mag.flux_w - 1.0).abs() < 1e-5);
+
+        let negative = Observation::from_mjd_fluxerr(0, 1, MJD0, -1.0, 0.5, MAG_ZP_F32);
+        assert!(negative.mag.is_nan());
+        assert_eq!(negative.flux_w, 4.0);
+
+        // Bad errors are kept as is, they are dropped by the quality filter only
+        let nan_err = Observation::from_mjd_magerr(0, 1, MJD0, 15.0, f32::NAN, MAG_ZP_F32);
+        assert!(nan_err.w.is_nan());
+        let negative_err = Observation::from_mjd_magerr(0, 1, MJD0, 15.0, -0.5, MAG_ZP_F32);
+        assert_eq!(negative_err.w, 4.0);
+    }
+
+    #[test]
+    fn zero_points_parse() {
+        let registry = PassbandRegistry::default();
+        let passbands = registry.parse_names("gri");
+        let zero_points = ZeroPoints::parse(&registry, "g=26.3, 23.9").unwrap();
+        assert_eq!(zero_points.get(1), 26.3);
+        assert_eq!(zero_points.get(2), 23.9);
+        assert_eq!(zero_points.uniform(&passbands[1..]), Some(23.9));
+        assert_eq!(zero_points.uniform(&passbands), None);
+        assert!(ZeroPoints::parse(&registry, "g=AB").is_err());
+        assert!(ZeroPoints::parse(&registry, "z=23.9").is_err());
+
+        let flux = Observation::from_mjd_magerr(0, 1, MJD0, 26.3, 0.1, zero_points.get(1)).flux;
+        assert!((flux - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn source_time_offset() {
+        // Archival epoch, f32 time relative to MJD0 would have resolution of a few seconds
+        let mjd = [40000.123456, 40000.123457];
+        let mut source = Source::new(0);
+        for &mjd in mjd.iter() {
+            source.push_observation(&Observation::from_mjd_magerr(0, 1, mjd, 15.0, 0.1, 25.0));
+        }
+        assert_eq!(source.t0, 40000.0 - MJD0);
+        let t = &source.lcs[&1].t;
+        assert!(((t[1] - t[0]) as f64 - 1e-6).abs() < 1e-7);
+
+        let mut other = Source::new(0);
+        other.push_observation(&Observation::from_mjd_magerr(
+            0, 2, 40001.5, 15.0, 0.1, 25.0,
+        ));
+        source.append(other);
+        assert_eq!(source.lcs[&2].t, [1.5]);
+
+        let times: Vec<_> = source.iter_observations().map(|obs| obs.t + MJD0).collect();
+        assert!((times[1] - mjd[1]).abs() < 1e-9);
+        source.set_time_offset(0.0);
+        assert_eq!(source.lcs[&2].t, [(40001.5 - MJD0) as f32]);
+    }
+
+    #[test]
+    fn source_time_offset_non_finite_first_time() {
+        let mut source = Source::new(0);
+        for mjd in [f64::NAN, 40000.5, 40001.5] {
+            source.push_observation(&Observation::from_mjd_magerr(0, 1, mjd, 15.0, 0.1, 25.0));
         }
+        assert_eq!(source.t0, 40000.0 - MJD0);
+        source.set_time_offset(0.0);
+        let t = &source.lcs[&1].t;
+        assert!(t[0].is_nan());
+        assert_eq!(t[1..], [(40000.5 - MJD0) as f32, (40001.5 - MJD0) as f32]);
     }
 
     #[test]
     #[should_panic]
-    fn passband_from_lcs_indix_panic() {
-        let i = Passband::n_filters();
-        let _passband = Passband::from_lcs_index(i);
+    fn passband_registry_unknown_name_panic() {
+        let _passband = PassbandRegistry::default().get("c");
     }
 }
@@ -1,24 +1,37 @@
-use light_curve_common::linspace;
-use light_curve_interpol::Interpolator;
-
 #[cfg(feature = "hdf")]
 use std::fs::File;
 #[cfg(feature = "hdf")]
 use std::io::Write;
 #[cfg(feature = "hdf")]
 use std::path::Path;
+use std::time::SystemTime;
+
+mod affinity;
+
+#[cfg(feature = "arrow")]
+mod arrow_db;
+#[cfg(feature = "arrow")]
+use arrow_db::{ArrowFormat, ArrowSourceDataBase};
+
+mod checkpoint;
+use checkpoint::Checkpoint;
 
 pub mod ch;
 use ch::CHSourceDataBase;
 
+mod color;
+
 pub mod config;
-use config::{Config, DataBase};
+use config::{Config, DataBase, ProgressTotal};
+
+mod csv_db;
+use csv_db::CsvSourceDataBase;
 
 mod constants;
 pub use constants::*;
 
 mod dump;
-use dump::Dumper;
+use dump::{Dumper, OutputFile, SourceCounts};
 
 mod features;
 
@@ -28,6 +41,27 @@ mod hdf;
 use hdf::Hdf5Cache;
 
 mod lc;
+use lc::Source;
+
+mod manifest;
+use manifest::Manifest;
+
+mod merge;
+pub use merge::merge;
+
+mod metrics;
+
+mod npy;
+
+#[cfg(feature = "arrow")]
+mod parquet_dump;
+
+mod progress;
+
+mod quality;
+use quality::RejectionCounts;
+
+mod shard;
 
 mod traits;
 #[cfg(feature = "hdf")]
@@ -35,19 +69,34 @@ use traits::Cache;
 use traits::{ObservationsToSources, SourceDataBase};
 
 pub fn run(config: Config) {
-    let mut dumper = Dumper::new(&config.passbands);
+    let started = SystemTime::now();
+    let (path, resolved_args) = &config.resolved_args;
+    std::fs::write(path, resolved_args).expect("Cannot write resolved config");
+
+    let mut dumper = Dumper::new(&config.passbands, &config.photometry.zero_points);
+    dumper.set_output_format(config.output_format);
+    dumper.set_ordered(config.ordered);
+    dumper.set_thread_config(config.thread_config.clone());
+    dumper.set_failure_policy(config.failure_policy, config.failure_path.clone());
+
+    if let Some(cc) = &config.checkpoint_config {
+        dumper.set_checkpoint(cc.path.clone(), cc.interval);
+        if cc.resume {
+            dumper.set_resume(Checkpoint::load(&cc.path));
+        }
+    }
 
     if let Some(ref sid_path) = config.sid_path {
         dumper.set_sid_writer(sid_path.clone());
     }
 
+    dumper.set_time_offset(config.time_offset);
+    if let Some(t0_path) = &config.t0_path {
+        dumper.set_t0_writer(t0_path.clone());
+    }
+
     if let Some(ic) = &config.interpolation_config {
-        let interpolator = Interpolator {
-            target_x: linspace(58194.5_f32, 58482.5, 145),
-            left: 0.,
-            right: 0.,
-        };
-        dumper.set_interpolator(ic.path.clone(), interpolator);
+        dumper.set_interpolator(ic.path.clone(), ic.json_path.clone(), ic.grid(), ic.fill);
     }
 
     if let Some(fc) = &config.feature_config {
@@ -56,20 +105,71 @@ pub fn run(config: Config) {
             fc.name_path.clone(),
             fc.json_path.clone(),
             fc.version.magn_extractor(),
-            fc.version.flux_extractor(),
+            config
+                .passbands
+                .iter()
+                .map(|passband| {
+                    let zero_point = config.photometry.zero_points.get(passband.code);
+                    fc.version.flux_extractor(zero_point)
+                })
+                .collect(),
+            fc.color_features.clone(),
         );
     }
 
-    dump_data(&mut dumper, &config);
+    if let Some(sc) = &config.sink_config {
+        dumper.set_clickhouse_sink(sc.url.clone(), sc.table.clone());
+    }
+
+    if let Some(ss) = &config.source_selection {
+        dumper.set_source_selection(ss.clone(), config.skipped_path.clone());
+    }
+
+    if let Some(qf) = &config.quality_filter {
+        dumper.set_quality_filter(qf.clone());
+    }
+
+    if let Some(mc) = &config.metrics_config {
+        metrics::serve(&mc.address, dumper.metrics());
+    }
+
+    // Counted total is set by dump_from_db
+    if let Some(pc) = config.progress_config {
+        let total = match pc.total {
+            Some(ProgressTotal::Sources(total)) => Some(total),
+            _ => None,
+        };
+        dumper.set_progress(pc, total);
+    }
+
+    let (counts, rejected) = dump_data(&mut dumper, &config);
+    if config.source_selection.is_some() || config.quality_filter.is_some() {
+        eprintln!("Rejected {}", rejected);
+    }
 
     dumper.write_names();
     dumper.write_json();
+
+    let mut outputs = dumper.output_files();
+    outputs.push(OutputFile {
+        path: path.clone(),
+        arrays: vec![],
+    });
+    let manifest = Manifest {
+        config: &config,
+        outputs,
+        counts,
+        started,
+        finished: SystemTime::now(),
+    };
+    manifest.save(&config.manifest_path);
 }
 
 #[cfg(feature = "hdf")]
-fn dump_data(dumper: &mut Dumper, config: &Config) {
+fn dump_data(dumper: &mut Dumper, config: &Config) -> (SourceCounts, RejectionCounts) {
     let read_cache = match &config.cache_config {
         Some(cc) => {
+            let photometry = config.photometry.json(&config.passbands);
             let cache = Box::new(Hdf5Cache {
                 path: cc.data_path.clone(),
             });
@@ -80,10 +180,20 @@ fn dump_data(dumper: &mut Dumper, config: &Config) {
                     query_from_file, config.sql_query,
                     "Cached SQL query mismatched specified one"
                 );
+                let photometry_cache = std::fs::read(&cc.photometry_path)
+                    .expect("Cannot read cached photometry, the cache must be re-created");
+                let photometry_from_file: serde_json::Value =
+                    serde_json::from_slice(&photometry_cache).unwrap();
+                assert_eq!(
+                    photometry_from_file, photometry,
+                    "Cached brightness type or zero points mismatched specified ones"
+                );
                 Some(cache)
             } else {
                 let mut query_file = File::create(cc.query_path.clone()).unwrap();
                 write!(query_file, "{}", config.sql_query).unwrap();
+                let photometry_file = File::create(cc.photometry_path.clone()).unwrap();
+                serde_json::to_writer(photometry_file, &photometry).unwrap();
                 dumper.set_write_cache(cache);
                 None
             }
@@ -92,25 +202,64 @@ fn dump_data(dumper: &mut Dumper, config: &Config) {
     };
 
     match read_cache {
-        Some(cache) => {
-            dumper.dump_query_iter(cache.reader());
-        }
+        Some(cache) => dumper.dump_query_iter(cache.reader()),
         None => dump_from_db(dumper, config),
     }
 }
 
 #[cfg(not(feature = "hdf"))]
-fn dump_data(dumper: &mut Dumper, config: &Config) {
-    dump_from_db(dumper, config);
+fn dump_data(dumper: &mut Dumper, config: &Config) -> (SourceCounts, RejectionCounts) {
+    dump_from_db(dumper, config)
 }
 
-fn dump_from_db(dumper: &mut Dumper, config: &Config) {
+fn dump_from_db(dumper: &mut Dumper, config: &Config) -> (SourceCounts, RejectionCounts) {
     match config.database {
         DataBase::ClickHouse => {
-            let mut source_db = CHSourceDataBase::new(&config.connection_config);
+            let mut source_db = CHSourceDataBase::new(
+                &config.connection_config,
+                config.photometry.clone(),
+                dumper.metrics(),
+            );
+            if let Some(pc) = config.progress_config {
+                if pc.total == Some(ProgressTotal::Count) {
+                    dumper.set_progress(pc, Some(source_db.count_sources(&config.sql_query)));
+                }
+            }
+            let query = source_db.query(&config.sql_query);
+            if config.array_rows {
+                dumper.dump_query_iter(query.array_rows(config.light_curves_are_sorted))
+            } else {
+                let source_iter = query.into_iter().sources(config.light_curves_are_sorted);
+                dumper.dump_query_iter(source_iter)
+            }
+        }
+        DataBase::Csv => {
+            let mut source_db = CsvSourceDataBase::new(&config.file_config);
+            let query = source_db.query(&config.sql_query);
+            let source_iter = query.into_iter().sources(config.light_curves_are_sorted);
+            dumper.dump_query_iter(filter_shard(config, source_iter))
+        }
+        #[cfg(feature = "arrow")]
+        DataBase::Parquet | DataBase::ArrowIpc => {
+            let format = match config.database {
+                DataBase::Parquet => ArrowFormat::Parquet,
+                _ => ArrowFormat::Ipc,
+            };
+            let mut source_db = ArrowSourceDataBase::new(format, &config.file_config);
             let query = source_db.query(&config.sql_query);
             let source_iter = query.into_iter().sources(config.light_curves_are_sorted);
-            dumper.dump_query_iter(source_iter);
+            dumper.dump_query_iter(filter_shard(config, source_iter))
         }
     }
 }
+
+/// Select sources of the shard for DBs which cannot do it by themselves
+fn filter_shard<'a>(
+    config: &'a Config,
+    source_iter: impl Iterator<Item = Source> + 'a,
+) -> impl Iterator<Item = Source> + 'a {
+    source_iter.filter(move |source| match &config.shard {
+        Some(shard) => shard.contains(source.sid),
+        None => true,
+    })
+}
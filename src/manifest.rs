@@ -0,0 +1,106 @@
+use crate::config::{strip_password, Config, OutputFormat};
+use crate::dump::{OutputFile, SourceCounts};
+use crate::npy::HEADER_LEN as NPY_HEADER_LEN;
+
+use std::fs::{self, File};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Description of run outputs and their provenance, it is written to `manifest*.json`
+pub struct Manifest<'a> {
+    pub config: &'a Config,
+    pub outputs: Vec<OutputFile>,
+    pub counts: SourceCounts,
+    pub started: SystemTime,
+    pub finished: SystemTime,
+}
+
+impl<'a> Manifest<'a> {
+    pub fn save(&self, path: &str) {
+        let json = serde_json::to_string_pretty(&self.to_json()).unwrap();
+        fs::write(path, json).expect("Cannot write manifest file");
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let files: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|output| self.file_json(output))
+            .collect();
+        let connection = match self.config.connection_config.as_str() {
+            "" => None,
+            url => Some(strip_password(url)),
+        };
+        let features = self.config.feature_config.as_ref().map(|fc| {
+            serde_json::json!({
+                "version": fc.version.name(),
+                "json": fc.json_path,
+            })
+        });
+        serde_json::json!({
+            "feat_extr_version": env!("CARGO_PKG_VERSION"),
+            "light_curve_feature_version": env!("LIGHT_CURVE_FEATURE_VERSION"),
+            "started": unix_time(self.started),
+            "finished": unix_time(self.finished),
+            "sql_query": self.config.sql_query,
+            "connection": connection,
+            "features": features,
+            "sources": {
+                "resumed": self.counts.resumed,
+                "read": self.counts.read,
+                "skipped": self.counts.skipped,
+                "failed": self.counts.failed,
+                "written": self.counts.written,
+            },
+            "files": files,
+        })
+    }
+
+    fn file_json(&self, output: &OutputFile) -> serde_json::Value {
+        let size = fs::metadata(&output.path)
+            .expect("Cannot read output file metadata")
+            .len() as usize;
+        let arrays: Vec<_> = output
+            .arrays
+            .iter()
+            .map(|array| {
+                let item_size: usize = array.descr[2..].parse().unwrap();
+                let row_bytes = item_size * array.row_shape.iter().product::<usize>();
+                // Raw outputs may be appended by resumed runs, so rows are counted by their size
+                let rows = match self.config.output_format {
+                    OutputFormat::Dat => size / row_bytes,
+                    OutputFormat::Npy => (size - NPY_HEADER_LEN) / row_bytes,
+                    #[allow(unreachable_patterns)]
+                    _ => self.counts.written,
+                };
+                let shape: Vec<_> = std::iter::once(rows)
+                    .chain(array.row_shape.iter().copied())
+                    .collect();
+                serde_json::json!({
+                    "name": array.name,
+                    "dtype": array.descr,
+                    "shape": shape,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "path": output.path,
+            "size": size,
+            "md5": md5_hex(&output.path),
+            "arrays": arrays,
+        })
+    }
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .expect("System time is before Unix epoch")
+        .as_secs_f64()
+}
+
+fn md5_hex(path: &str) -> String {
+    let mut file = File::open(path).expect("Cannot open output file");
+    let mut context = md5::Context::new();
+    io::copy(&mut file, &mut context).expect("Cannot read output file");
+    format!("{:x}", context.compute())
+}
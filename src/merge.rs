@@ -0,0 +1,134 @@
+use crate::config::MergeConfig;
+use crate::npy::NpyHeader;
+#[cfg(feature = "arrow")]
+use crate::parquet_dump;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Outputs which shards are concatenated
+const DATA_OUTPUTS: [(&str, &str); 12] = [
+    ("sid", ".dat"),
+    ("sid", ".npy"),
+    ("t0", ".dat"),
+    ("t0", ".npy"),
+    ("feature", ".dat"),
+    ("feature", ".npy"),
+    ("feature", ".parquet"),
+    ("flux", ".dat"),
+    ("flux", ".npy"),
+    ("flux", ".parquet"),
+    ("failure", ".tsv"),
+    ("skipped", ".tsv"),
+];
+
+/// Outputs which must be the same for all shards
+const META_OUTPUTS: [(&str, &str); 3] = [
+    ("feature", ".name"),
+    ("feature", ".json"),
+    ("flux", ".json"),
+];
+
+/// Concatenate outputs of sharded runs into outputs of a single run
+///
+/// Outputs absent for the first shard are ignored, e.g. flux files of a run without --interpol
+pub fn merge(config: &MergeConfig) {
+    assert!(
+        shard_paths(config, "output", ".hdf5").is_none(),
+        "HDF5 outputs cannot be merged, use dat, npy or parquet format"
+    );
+    for (basename, ext) in META_OUTPUTS {
+        let paths = match shard_paths(config, basename, ext) {
+            Some(paths) => paths,
+            None => continue,
+        };
+        let first = fs::read(&paths[0]).expect("Cannot read shard output");
+        for path in paths[1..].iter() {
+            let other = fs::read(path).expect("Cannot read shard output");
+            assert_eq!(first, other, "{} differs from {}", path, paths[0]);
+        }
+        fs::write(config.get_path(basename, None, ext), first).expect("Cannot write merged output");
+    }
+    for (basename, ext) in DATA_OUTPUTS {
+        let paths = match shard_paths(config, basename, ext) {
+            Some(paths) => paths,
+            None => continue,
+        };
+        let merged_path = config.get_path(basename, None, ext);
+        match ext {
+            ".npy" => concatenate_npy(&paths, &merged_path),
+            #[cfg(feature = "arrow")]
+            ".parquet" => parquet_dump::concatenate(&paths, &merged_path),
+            #[cfg(not(feature = "arrow"))]
+            ".parquet" => panic!("the application is built without arrow support"),
+            _ => concatenate(&paths, &merged_path),
+        }
+    }
+}
+
+fn concatenate(paths: &[String], merged_path: &str) {
+    let file = File::create(merged_path).unwrap();
+    let mut writer = BufWriter::new(file);
+    for path in paths.iter() {
+        let mut reader = File::open(path).expect("Cannot open shard output");
+        io::copy(&mut reader, &mut writer).expect("Cannot write merged output");
+    }
+    writer.flush().expect("Cannot write merged output");
+}
+
+/// Concatenate arrays along the first axis
+fn concatenate_npy(paths: &[String], merged_path: &str) {
+    let mut readers: Vec<_> = paths
+        .iter()
+        .map(|path| File::open(path).expect("Cannot open shard output"))
+        .collect();
+    let headers: Vec<_> = readers.iter_mut().map(NpyHeader::read).collect();
+    for (path, header) in paths.iter().zip(headers.iter()).skip(1) {
+        assert!(
+            header.descr == headers[0].descr && header.row_shape == headers[0].row_shape,
+            "{} has different dtype or shape than {}",
+            path,
+            paths[0]
+        );
+    }
+    let mut merged_header = headers[0].clone();
+    merged_header.rows = headers.iter().map(|header| header.rows).sum();
+
+    let file = File::create(merged_path).unwrap();
+    let mut writer = BufWriter::new(file);
+    writer
+        .write_all(&merged_header.to_bytes())
+        .expect("Cannot write merged output");
+    for (reader, header) in readers.iter_mut().zip(headers.iter()) {
+        // Data of interrupted runs can be longer than the header says
+        let data_len = (header.rows * header.row_bytes()) as u64;
+        let copied =
+            io::copy(&mut reader.take(data_len), &mut writer).expect("Cannot write merged output");
+        assert_eq!(
+            copied, data_len,
+            "Shard output is shorter than its header says"
+        );
+    }
+    writer.flush().expect("Cannot write merged output");
+}
+
+/// Paths of the output of every shard, None if the first shard has no such output
+fn shard_paths(config: &MergeConfig, basename: &str, ext: &str) -> Option<Vec<String>> {
+    let paths: Vec<_> = config
+        .shards
+        .iter()
+        .map(|shard| config.get_path(basename, Some(shard), ext))
+        .collect();
+    if !Path::new(&paths[0]).exists() {
+        return None;
+    }
+    for path in paths.iter() {
+        assert!(
+            Path::new(path).exists(),
+            "Shard output {} is not found",
+            path
+        );
+    }
+    Some(paths)
+}
@@ -0,0 +1,256 @@
+use crate::progress::ProgressCounters;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Upper bounds of latency histogram buckets, seconds
+const LATENCY_BUCKETS: [f64; 8] = [1e-5, 1e-4, 1e-3, 1e-2, 0.1, 1.0, 10.0, 100.0];
+
+/// Histogram of durations with cumulative buckets of Prometheus
+#[derive(Debug, Default)]
+pub struct Histogram {
+    // Non-cumulative counts of LATENCY_BUCKETS, the last one is for larger durations
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let idx = LATENCY_BUCKETS.partition_point(|&le| le < seconds);
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let mut count = 0;
+        for (i, le) in LATENCY_BUCKETS
+            .iter()
+            .map(|le| le.to_string())
+            .chain(std::iter::once("+Inf".to_owned()))
+            .enumerate()
+        {
+            count += self.counts[i].load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count).unwrap();
+        }
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 * 1e-9;
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, sum).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, count).unwrap();
+    }
+}
+
+/// Metrics of a run exposed in Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub counters: ProgressCounters,
+    /// Sources having evaluation errors
+    pub failed: AtomicUsize,
+    /// Evaluation errors by extractor, like "magn_g", "flux_r" or "color", errors of different
+    /// features of the same extractor are counted together
+    failures: Mutex<BTreeMap<String, u64>>,
+    /// Time to get a source from the input
+    pub read_latency: Histogram,
+    /// Time to filter and evaluate a source
+    pub eval_latency: Histogram,
+    /// Time to write evaluation results of a source
+    pub write_latency: Histogram,
+    /// Blocks received from ClickHouse
+    pub db_blocks: AtomicUsize,
+}
+
+impl Metrics {
+    /// Count evaluation errors of a source, they are prefixed by extractor name
+    pub fn add_failures(&self, errors: &[String]) {
+        if errors.is_empty() {
+            return;
+        }
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        let mut failures = self.failures.lock().unwrap();
+        for error in errors {
+            let extractor = error.split(':').next().unwrap();
+            *failures.entry(extractor.to_owned()).or_default() += 1;
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = &self.counters;
+        let read = counters.read.load(Ordering::Relaxed);
+        let evaluated = counters.evaluated.load(Ordering::Relaxed);
+        let written = counters.written.load(Ordering::Relaxed);
+        let skipped = counters.skipped.load(Ordering::Relaxed);
+        let values = [
+            (
+                "sources_read_total",
+                "counter",
+                "Sources sent to evaluation",
+                read,
+            ),
+            (
+                "sources_evaluated_total",
+                "counter",
+                "Evaluated sources",
+                evaluated,
+            ),
+            (
+                "sources_written_total",
+                "counter",
+                "Sources processed by the writer, including failed ones",
+                written,
+            ),
+            (
+                "sources_skipped_total",
+                "counter",
+                "Sources skipped by source selection",
+                skipped,
+            ),
+            (
+                "sources_failed_total",
+                "counter",
+                "Sources having evaluation errors",
+                self.failed.load(Ordering::Relaxed),
+            ),
+            (
+                "observations_read_total",
+                "counter",
+                "Observations of read sources",
+                counters.observations.load(Ordering::Relaxed),
+            ),
+            (
+                "db_blocks_total",
+                "counter",
+                "Blocks received from ClickHouse",
+                self.db_blocks.load(Ordering::Relaxed),
+            ),
+            (
+                "eval_queue_sources",
+                "gauge",
+                "Sources waiting for evaluation or being evaluated",
+                read.saturating_sub(evaluated),
+            ),
+            (
+                "write_queue_sources",
+                "gauge",
+                "Evaluated sources waiting for the writer",
+                evaluated.saturating_sub(written + skipped),
+            ),
+        ];
+        for (name, type_, help, value) in values {
+            writeln!(out, "# HELP feat_extr_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE feat_extr_{} {}", name, type_).unwrap();
+            writeln!(out, "feat_extr_{} {}", name, value).unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP feat_extr_failures_total Evaluation errors by extractor, \
+            like magn_g, flux_r or color, rather than by feature"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE feat_extr_failures_total counter").unwrap();
+        for (extractor, count) in self.failures.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "feat_extr_failures_total{{extractor=\"{}\"}} {}",
+                escape_label(extractor),
+                count
+            )
+            .unwrap();
+        }
+
+        let name = "feat_extr_stage_duration_seconds";
+        writeln!(out, "# HELP {} Processing time of a source by stage", name).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (stage, histogram) in [
+            ("read", &self.read_latency),
+            ("eval", &self.eval_latency),
+            ("write", &self.write_latency),
+        ] {
+            histogram.render(name, &format!("stage=\"{}\"", stage), &mut out);
+        }
+        out
+    }
+}
+
+/// Label value with escaped backslashes, double quotes and line feeds
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve metrics over HTTP in a background thread, every request gets the metrics
+pub fn serve(address: &str, metrics: Arc<Metrics>) {
+    let listener = TcpListener::bind(address)
+        .unwrap_or_else(|err| panic!("Cannot bind metrics address {}: {}", address, err));
+    thread::spawn(move || {
+        // Connections are handled by their own threads, so a slow client cannot block the other
+        // ones. Failed connection must not stop the run
+        for stream in listener.incoming().flatten() {
+            let metrics = metrics.clone();
+            thread::spawn(move || respond(stream, &metrics));
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // Read the request head, its content is not used
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 1 << 16 {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    let body = metrics.render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.counters.read.fetch_add(3, Ordering::Relaxed);
+        metrics.counters.evaluated.fetch_add(3, Ordering::Relaxed);
+        metrics.counters.written.fetch_add(1, Ordering::Relaxed);
+        metrics.counters.skipped.fetch_add(1, Ordering::Relaxed);
+        metrics.add_failures(&["magn_g: too short".to_owned(), "flux_g: error".to_owned()]);
+        metrics.add_failures(&["magn_g: too short".to_owned()]);
+        metrics.add_failures(&["a\"b\\c: error".to_owned()]);
+        metrics.eval_latency.observe(Duration::from_millis(5));
+        let text = metrics.render();
+        assert!(text.contains("\nfeat_extr_sources_read_total 3\n"));
+        assert!(text.contains("\nfeat_extr_sources_failed_total 3\n"));
+        assert!(text.contains("\nfeat_extr_sources_written_total 1\n"));
+        assert!(text.contains("\nfeat_extr_sources_skipped_total 1\n"));
+        assert!(text.contains("\nfeat_extr_write_queue_sources 1\n"));
+        assert!(text.contains("\nfeat_extr_failures_total{extractor=\"a\\\"b\\\\c\"} 1\n"));
+        assert!(text.contains("\nfeat_extr_failures_total{extractor=\"magn_g\"} 2\n"));
+        assert!(text.contains(
+            "\nfeat_extr_stage_duration_seconds_bucket{stage=\"eval\",le=\"0.001\"} 0\n"
+        ));
+        assert!(text
+            .contains("\nfeat_extr_stage_duration_seconds_bucket{stage=\"eval\",le=\"0.01\"} 1\n"));
+        assert!(text.contains("\nfeat_extr_stage_duration_seconds_count{stage=\"eval\"} 1\n"));
+    }
+}
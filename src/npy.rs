@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Size of the header, it is fixed so the header can be rewritten when row count is known
+pub const HEADER_LEN: usize = 128;
+
+/// Header of .npy file of version 1.0, the first dimension of the array is the row count
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyHeader {
+    /// NumPy type string, like "<f4"
+    pub descr: String,
+    pub rows: usize,
+    /// Shape of a single row, it is empty for 1-D array
+    pub row_shape: Vec<usize>,
+}
+
+impl NpyHeader {
+    /// Header of native-endian array, `dtype` is type string without byte order, like "f4"
+    pub fn native(dtype: &str, row_shape: &[usize]) -> Self {
+        let byte_order = if cfg!(target_endian = "little") {
+            '<'
+        } else {
+            '>'
+        };
+        Self {
+            descr: format!("{}{}", byte_order, dtype),
+            rows: 0,
+            row_shape: row_shape.to_vec(),
+        }
+    }
+
+    pub fn row_bytes(&self) -> usize {
+        let item_size: usize = self.descr[2..].parse().unwrap();
+        item_size * self.row_shape.iter().product::<usize>()
+    }
+
+    /// Set row count from the size of array data
+    pub fn set_rows_from_data_len(&mut self, data_len: usize) {
+        assert_eq!(
+            data_len % self.row_bytes(),
+            0,
+            "array data is not a whole number of rows"
+        );
+        self.rows = data_len / self.row_bytes();
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let shape: Vec<_> = std::iter::once(self.rows)
+            .chain(self.row_shape.iter().copied())
+            .map(|x| x.to_string())
+            .collect();
+        // One-element tuple must have trailing comma
+        let shape = match shape.len() {
+            1 => format!("({},)", shape[0]),
+            _ => format!("({})", shape.join(", ")),
+        };
+        let dict = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            self.descr, shape
+        );
+        let dict_len = HEADER_LEN - MAGIC.len() - 4;
+        assert!(dict.len() < dict_len, "npy header is too long");
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(dict_len as u16).to_le_bytes());
+        bytes.extend_from_slice(dict.as_bytes());
+        bytes.resize(HEADER_LEN - 1, b' ');
+        bytes.push(b'\n');
+        bytes
+    }
+
+    /// Parse header written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != HEADER_LEN || !bytes.starts_with(MAGIC) {
+            return Err("not a npy file written by this application".to_owned());
+        }
+        let dict = std::str::from_utf8(&bytes[MAGIC.len() + 4..])
+            .map_err(|_| "npy header is not a valid string".to_owned())?;
+        // Value of the key up to the closing character, the opening one is skipped
+        let value = |key: &str, close: char| {
+            let pattern = format!("'{}': ", key);
+            let start = dict
+                .find(&pattern)
+                .ok_or_else(|| format!("npy header has no {}", key))?
+                + pattern.len()
+                + 1;
+            let len = dict[start..]
+                .find(close)
+                .ok_or_else(|| format!("npy header {} is invalid", key))?;
+            Ok::<_, String>(&dict[start..start + len])
+        };
+        let descr = value("descr", '\'')?.to_owned();
+        let shape = value("shape", ')')?
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "npy header shape is invalid".to_owned())?;
+        let (&rows, row_shape) = shape
+            .split_first()
+            .ok_or_else(|| "npy header shape is empty".to_owned())?;
+        Ok(Self {
+            descr,
+            rows,
+            row_shape: row_shape.to_vec(),
+        })
+    }
+
+    pub fn read(file: &mut File) -> Self {
+        let mut bytes = vec![0; HEADER_LEN];
+        file.read_exact(&mut bytes).expect("Cannot read npy header");
+        Self::from_bytes(&bytes).unwrap()
+    }
+
+    /// Rewrite the header in the beginning of the file, file position is moved to its end
+    pub fn rewrite(&self, file: &mut File) {
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&self.to_bytes())
+            .expect("Cannot write npy header");
+        file.seek(SeekFrom::End(0)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn npy_header_roundtrip() {
+        for row_shape in [vec![], vec![42]] {
+            let mut header = NpyHeader::native("f4", &row_shape);
+            header.rows = 1 << 40;
+            let bytes = header.to_bytes();
+            assert_eq!(bytes.len(), HEADER_LEN);
+            assert_eq!(NpyHeader::from_bytes(&bytes).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn npy_header_format() {
+        let mut header = NpyHeader::native("u8", &[]);
+        header.set_rows_from_data_len(24);
+        let bytes = header.to_bytes();
+        let dict = std::str::from_utf8(&bytes[10..]).unwrap();
+        let expected = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': (3,), }}",
+            header.descr
+        );
+        assert!(dict.starts_with(&expected));
+        assert!(dict.ends_with(" \n"));
+        assert_eq!(
+            u16::from_le_bytes([bytes[8], bytes[9]]) as usize,
+            HEADER_LEN - 10
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use arrow::array::{ArrayRef, FixedSizeListArray, Float32Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Number of rows buffered before they are written as a row group
+const ROW_GROUP_SIZE: usize = 1 << 16;
+
+/// Key of the dump JSON in Parquet key-value metadata
+pub const JSON_METADATA_KEY: &str = "feat_extr_json";
+
+/// Writes sid and f32 dump values to Parquet file
+///
+/// Every value has its own column if value names are given, otherwise values of a source are
+/// written into a single fixed-size list column named `column`
+pub struct ParquetValueWriter {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    named_columns: bool,
+    row_len: usize,
+    sids: Vec<u64>,
+    values: Vec<f32>,
+}
+
+impl ParquetValueWriter {
+    pub fn new(path: &str, column: &str, names: &[&str], row_len: usize, json: &str) -> Self {
+        let named_columns = !names.is_empty();
+        let mut fields = vec![Field::new("sid", DataType::UInt64, false)];
+        if named_columns {
+            assert_eq!(names.len(), row_len, "every value must have a name");
+            fields.extend(
+                names
+                    .iter()
+                    .map(|name| Field::new(*name, DataType::Float32, false)),
+            );
+        } else {
+            fields.push(Field::new(
+                column,
+                DataType::FixedSizeList(Self::list_item(), row_len as i32),
+                false,
+            ));
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                JSON_METADATA_KEY.to_owned(),
+                json.to_owned(),
+            )]))
+            .build();
+        let file = File::create(path).unwrap();
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))
+            .expect("Cannot create Parquet writer");
+        Self {
+            writer,
+            schema,
+            named_columns,
+            row_len,
+            sids: Vec::with_capacity(ROW_GROUP_SIZE),
+            values: Vec::with_capacity(ROW_GROUP_SIZE * row_len),
+        }
+    }
+
+    fn list_item() -> Arc<Field> {
+        Arc::new(Field::new("item", DataType::Float32, false))
+    }
+
+    /// `data` is native-endian f32 values of the source
+    pub fn write(&mut self, sid: u64, data: &[u8]) {
+        assert_eq!(data.len(), 4 * self.row_len, "unexpected number of values");
+        self.sids.push(sid);
+        self.values.extend(
+            data.chunks_exact(4)
+                .map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap())),
+        );
+        if self.sids.len() == ROW_GROUP_SIZE {
+            self.write_row_group();
+        }
+    }
+
+    fn write_row_group(&mut self) {
+        if self.sids.is_empty() {
+            return;
+        }
+        let sids = std::mem::take(&mut self.sids);
+        let values = std::mem::take(&mut self.values);
+        let mut columns: Vec<ArrayRef> = vec![Arc::new(UInt64Array::from(sids))];
+        if self.named_columns {
+            columns.extend((0..self.row_len).map(|i| {
+                let column: Float32Array = values
+                    .iter()
+                    .skip(i)
+                    .step_by(self.row_len)
+                    .copied()
+                    .collect();
+                Arc::new(column) as ArrayRef
+            }));
+        } else {
+            let list = FixedSizeListArray::try_new(
+                Self::list_item(),
+                self.row_len as i32,
+                Arc::new(Float32Array::from(values)),
+                None,
+            )
+            .unwrap();
+            columns.push(Arc::new(list));
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).unwrap();
+        self.writer
+            .write(&batch)
+            .expect("Cannot write to Parquet file");
+        self.writer.flush().expect("Cannot write to Parquet file");
+    }
+
+    /// Write buffered rows and Parquet footer
+    pub fn close(mut self) {
+        self.write_row_group();
+        self.writer.close().expect("Cannot write to Parquet file");
+    }
+}
+
+/// Concatenate Parquet files written by `ParquetValueWriter`, their schemas and JSON metadata must
+/// be the same
+pub fn concatenate(paths: &[String], merged_path: &str) {
+    let builders: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let file = File::open(path).expect("Cannot open shard output");
+            ParquetRecordBatchReaderBuilder::try_new(file).expect("Cannot read Parquet file")
+        })
+        .collect();
+    let json = |builder: &ParquetRecordBatchReaderBuilder<File>| {
+        builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|kv| kv.iter().find(|kv| kv.key == JSON_METADATA_KEY))
+            .and_then(|kv| kv.value.clone())
+    };
+    for (path, builder) in paths.iter().zip(builders.iter()).skip(1) {
+        assert!(
+            builder.schema() == builders[0].schema() && json(builder) == json(&builders[0]),
+            "{} has different columns or JSON metadata than {}",
+            path,
+            paths[0]
+        );
+    }
+
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .set_key_value_metadata(
+            json(&builders[0]).map(|json| vec![KeyValue::new(JSON_METADATA_KEY.to_owned(), json)]),
+        )
+        .build();
+    let file = File::create(merged_path).unwrap();
+    let mut writer = ArrowWriter::try_new(file, builders[0].schema().clone(), Some(properties))
+        .expect("Cannot create Parquet writer");
+    for builder in builders {
+        let reader = builder
+            .with_batch_size(ROW_GROUP_SIZE)
+            .build()
+            .expect("Cannot build Parquet reader");
+        for batch in reader {
+            let batch = batch.expect("Cannot read record batch");
+            writer.write(&batch).expect("Cannot write to Parquet file");
+        }
+    }
+    writer.close().expect("Cannot write to Parquet file");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow::array::AsArray;
+    use arrow::datatypes::Float32Type;
+
+    #[test]
+    fn parquet_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("feat_extr_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("feature.parquet");
+        let path = path.to_str().unwrap();
+
+        let mut writer = ParquetValueWriter::new(path, "features", &["a", "b"], 2, "{}");
+        for sid in 0..3_u64 {
+            let data: Vec<u8> = [sid as f32, -(sid as f32)]
+                .iter()
+                .flat_map(|x| x.to_ne_bytes())
+                .collect();
+            writer.write(sid, &data);
+        }
+        writer.close();
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap();
+        let kv = builder.metadata().file_metadata().key_value_metadata();
+        assert!(kv
+            .unwrap()
+            .iter()
+            .any(|kv| kv.key == JSON_METADATA_KEY && kv.value.as_deref() == Some("{}")));
+        let batches: Vec<_> = builder.build().unwrap().map(|b| b.unwrap()).collect();
+        assert_eq!(batches.len(), 1);
+        let b = batches[0]
+            .column_by_name("b")
+            .unwrap()
+            .as_primitive::<Float32Type>();
+        assert_eq!(b.values(), &[0.0, -1.0, -2.0]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
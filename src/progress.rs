@@ -0,0 +1,193 @@
+use crate::config::{ProgressConfig, ProgressFormat};
+use crate::metrics::Metrics;
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Counters updated by dumper threads, they are a part of `Metrics`
+#[derive(Debug, Default)]
+pub struct ProgressCounters {
+    /// Sources sent to evaluation
+    pub read: AtomicUsize,
+    /// Observations of read sources
+    pub observations: AtomicUsize,
+    /// Sources sent to the writer
+    pub evaluated: AtomicUsize,
+    /// Sources processed by the writer, including failed ones
+    pub written: AtomicUsize,
+    /// Sources skipped by source selection, they are not counted as written
+    pub skipped: AtomicUsize,
+}
+
+/// Periodically prints progress of a run to stderr
+pub struct ProgressReporter {
+    config: ProgressConfig,
+    /// Total number of sources including resumed ones, if it is known
+    total: Option<usize>,
+    resumed: usize,
+    metrics: Arc<Metrics>,
+    start: Instant,
+    // Time and observation count of the previous report
+    last: (Instant, usize),
+}
+
+/// State of a run at the time of a report
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Snapshot {
+    elapsed: f64,
+    read: usize,
+    written: usize,
+    skipped: usize,
+    obs_per_sec: f64,
+    /// Sources waiting for evaluation or being evaluated
+    eval_queue: usize,
+    /// Evaluated sources waiting for the writer, including the reorder buffer in ordered mode
+    write_queue: usize,
+    /// Estimated time to finish, seconds
+    eta: Option<f64>,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        config: ProgressConfig,
+        total: Option<usize>,
+        resumed: usize,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let start = Instant::now();
+        Self {
+            config,
+            total,
+            resumed,
+            metrics,
+            start,
+            last: (start, 0),
+        }
+    }
+
+    /// Report every interval until `stop` is disconnected and then report the final state
+    pub fn run(mut self, stop: Receiver<()>) {
+        loop {
+            let stopped = !matches!(
+                stop.recv_timeout(self.config.interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+            let snapshot = self.snapshot();
+            eprintln!("{}", self.format(&snapshot));
+            if stopped {
+                break;
+            }
+        }
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.start).as_secs_f64();
+        let counters = &self.metrics.counters;
+        let read = counters.read.load(Ordering::Relaxed);
+        let evaluated = counters.evaluated.load(Ordering::Relaxed);
+        let written = counters.written.load(Ordering::Relaxed);
+        let skipped = counters.skipped.load(Ordering::Relaxed);
+        let observations = counters.observations.load(Ordering::Relaxed);
+        let (last_time, last_observations) = self.last;
+        let obs_per_sec =
+            (observations - last_observations) as f64 / now.duration_since(last_time).as_secs_f64();
+        self.last = (now, observations);
+        Snapshot {
+            elapsed,
+            read,
+            written,
+            skipped,
+            obs_per_sec,
+            // Counters are updated independently, so they can be a bit inconsistent
+            eval_queue: read.saturating_sub(evaluated),
+            write_queue: evaluated.saturating_sub(written + skipped),
+            eta: self.eta(elapsed, written + skipped),
+        }
+    }
+
+    /// Remaining time assuming the mean source rate of the run, `processed` are written and
+    /// skipped sources
+    fn eta(&self, elapsed: f64, processed: usize) -> Option<f64> {
+        let remaining = self.total?.saturating_sub(self.resumed + processed);
+        match processed {
+            0 => None,
+            _ => Some(remaining as f64 * elapsed / processed as f64),
+        }
+    }
+
+    fn format(&self, snapshot: &Snapshot) -> String {
+        match self.config.format {
+            ProgressFormat::Text => {
+                let total = match self.total {
+                    Some(total) => format!(" of {}", total),
+                    None => String::new(),
+                };
+                let eta = match snapshot.eta {
+                    Some(eta) => format!(", ETA {}", format_duration(eta)),
+                    None => String::new(),
+                };
+                format!(
+                    "[{}] sources read: {}, written: {}{}, skipped: {}, {:.0} obs/s, \
+                    queued for eval: {}, for write: {}{}",
+                    format_duration(snapshot.elapsed),
+                    self.resumed + snapshot.read,
+                    self.resumed + snapshot.written,
+                    total,
+                    snapshot.skipped,
+                    snapshot.obs_per_sec,
+                    snapshot.eval_queue,
+                    snapshot.write_queue,
+                    eta
+                )
+            }
+            // Like the text, read and written sources include resumed ones
+            ProgressFormat::Json => serde_json::json!({
+                "elapsed": snapshot.elapsed,
+                "resumed": self.resumed,
+                "read": self.resumed + snapshot.read,
+                "written": self.resumed + snapshot.written,
+                "skipped": snapshot.skipped,
+                "total": self.total,
+                "obs_per_sec": snapshot.obs_per_sec,
+                "eval_queue": snapshot.eval_queue,
+                "write_queue": snapshot.write_queue,
+                "eta": snapshot.eta,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Duration like "1:02:03"
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn progress_eta_and_format() {
+        let config = ProgressConfig {
+            interval: Duration::from_secs(1),
+            format: ProgressFormat::Text,
+            total: None,
+        };
+        let reporter = ProgressReporter::new(config, Some(1000), 100, Default::default());
+        assert_eq!(reporter.eta(60.0, 0), None);
+        assert_eq!(reporter.eta(60.0, 300), Some(120.0));
+        assert_eq!(format_duration(3723.4), "1:02:03");
+    }
+}
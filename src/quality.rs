@@ -0,0 +1,344 @@
+use crate::lc::{flux_to_mag_weight_ratio, LightCurve, Passband, Source, MJD0};
+
+use std::fmt;
+use std::ops::AddAssign;
+
+/// Iterative sigma clipping of magnitudes around their median, observations with undefined
+/// magnitude, e.g. with negative native flux, are not clipped
+#[derive(Clone, Copy, Debug)]
+pub struct SigmaClip {
+    pub nsigma: f32,
+    pub max_iter: usize,
+}
+
+/// Observation filters applied to every light curve of a source before its evaluation
+///
+/// Filters are applied in the order of the fields
+#[derive(Clone, Debug, Default)]
+pub struct QualityFilter {
+    /// Drop observations with non-finite time, flux or flux weight, the weight is infinite for
+    /// zero brightness error and NaN for NaN one. Flux is checked because it is defined for both
+    /// magnitude and flux inputs, while magnitude is undefined for non-positive native flux
+    pub drop_non_finite: bool,
+    /// Magnitude errors smaller than the floor are raised to it, and flux errors are raised to
+    /// the floor converted to flux at the observed flux, so observations with undefined magnitude
+    /// are floored too
+    pub magerr_floor: Option<f32>,
+    /// Drop observations with larger magnitude error, observations with undefined magnitude are
+    /// kept
+    pub magerr_max: Option<f32>,
+    /// Merge observations having the same time into one with weighted mean magnitude and flux
+    pub merge_duplicates: bool,
+    pub sigma_clip: Option<SigmaClip>,
+}
+
+/// Selection of observations by time and of sources by number of observations, it is applied
+/// before `QualityFilter` and checked after it
+#[derive(Clone, Debug, Default)]
+pub struct SourceSelection {
+    pub mjd_min: Option<f64>,
+    pub mjd_max: Option<f64>,
+    /// Minimum number of observations in every passband
+    pub min_obs: Option<usize>,
+}
+
+/// Numbers of observations rejected by `SourceSelection` and `QualityFilter`, and number of
+/// sources skipped by `SourceSelection`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RejectionCounts {
+    pub time_window: usize,
+    pub non_finite: usize,
+    pub large_magerr: usize,
+    pub duplicate: usize,
+    pub clipped: usize,
+    pub skipped_sources: usize,
+}
+
+impl AddAssign for RejectionCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.time_window += other.time_window;
+        self.non_finite += other.non_finite;
+        self.large_magerr += other.large_magerr;
+        self.duplicate += other.duplicate;
+        self.clipped += other.clipped;
+        self.skipped_sources += other.skipped_sources;
+    }
+}
+
+impl fmt::Display for RejectionCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "observations out of time window: {}, non-finite: {}, with large magerr: {}, \
+            with duplicate time: {}, sigma clipped: {}; sources with too few observations: {}",
+            self.time_window,
+            self.non_finite,
+            self.large_magerr,
+            self.duplicate,
+            self.clipped,
+            self.skipped_sources
+        )
+    }
+}
+
+impl SourceSelection {
+    pub fn is_enabled(&self) -> bool {
+        self.mjd_min.is_some() || self.mjd_max.is_some() || self.min_obs.is_some()
+    }
+
+    /// Drop observations out of the time window, returns their number
+    pub fn apply_time_window(&self, source: &mut Source) -> usize {
+        if self.mjd_min.is_none() && self.mjd_max.is_none() {
+            return 0;
+        }
+        let t_min = self
+            .mjd_min
+            .map_or(f64::NEG_INFINITY, |mjd| mjd - MJD0 - source.t0);
+        let t_max = self
+            .mjd_max
+            .map_or(f64::INFINITY, |mjd| mjd - MJD0 - source.t0);
+        source
+            .lcs
+            .values_mut()
+            .map(|lc| lc.retain(|lc, i| lc.t[i] as f64 >= t_min && lc.t[i] as f64 <= t_max))
+            .sum()
+    }
+
+    /// Error message if the source has too few observations in any of the passbands
+    pub fn check_min_obs(&self, source: &Source, passbands: &[Passband]) -> Result<(), String> {
+        let min_obs = match self.min_obs {
+            Some(min_obs) => min_obs,
+            None => return Ok(()),
+        };
+        for passband in passbands {
+            let n = source.lc(passband).t.len();
+            if n < min_obs {
+                return Err(format!(
+                    "{} observations in {} passband, {} required",
+                    n, passband, min_obs
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl QualityFilter {
+    /// Is any filter enabled
+    pub fn is_enabled(&self) -> bool {
+        self.drop_non_finite
+            || self.magerr_floor.is_some()
+            || self.magerr_max.is_some()
+            || self.merge_duplicates
+            || self.sigma_clip.is_some()
+    }
+
+    pub fn apply(&self, source: &mut Source) -> RejectionCounts {
+        let mut counts = RejectionCounts::default();
+        for lc in source.lcs.values_mut() {
+            counts += self.apply_lc(lc);
+        }
+        counts
+    }
+
+    fn apply_lc(&self, lc: &mut LightCurve) -> RejectionCounts {
+        let mut counts = RejectionCounts::default();
+        if self.drop_non_finite {
+            counts.non_finite = lc.retain(|lc, i| {
+                lc.t[i].is_finite() && lc.flux[i].is_finite() && lc.flux_w[i].is_finite()
+            });
+        }
+        if let Some(floor) = self.magerr_floor {
+            let w_max = floor.powi(-2);
+            for i in 0..lc.t.len() {
+                // Comparisons keep NaN weights of undefined magnitudes
+                if lc.w[i] > w_max {
+                    lc.w[i] = w_max;
+                }
+                // Ratio is even in flux, so it is defined for negative fluxes too
+                let flux_w_max = w_max * flux_to_mag_weight_ratio(lc.flux[i]);
+                if lc.flux_w[i] > flux_w_max {
+                    lc.flux_w[i] = flux_w_max;
+                }
+            }
+        }
+        if let Some(magerr_max) = self.magerr_max {
+            let w_min = magerr_max.powi(-2);
+            counts.large_magerr = lc.retain(|lc, i| lc.mag[i].is_nan() || lc.w[i] >= w_min);
+        }
+        if self.merge_duplicates {
+            counts.duplicate = merge_duplicates(lc);
+        }
+        if let Some(sigma_clip) = self.sigma_clip {
+            counts.clipped = sigma_clip.apply(lc);
+        }
+        counts
+    }
+}
+
+impl SigmaClip {
+    /// Clip until no observation is rejected or `max_iter` iterations are done, returns number
+    /// of rejected observations
+    fn apply(&self, lc: &mut LightCurve) -> usize {
+        let mut rejected = 0;
+        for _ in 0..self.max_iter {
+            let mut sorted: Vec<_> = lc.mag.iter().copied().filter(|m| !m.is_nan()).collect();
+            if sorted.len() < 3 {
+                break;
+            }
+            sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+            let n = sorted.len();
+            let median = 0.5 * (sorted[(n - 1) / 2] + sorted[n / 2]);
+            let mean = sorted.iter().sum::<f32>() / n as f32;
+            let std =
+                (sorted.iter().map(|&m| (m - mean).powi(2)).sum::<f32>() / (n - 1) as f32).sqrt();
+            let threshold = self.nsigma * std;
+            let iter_rejected =
+                lc.retain(|lc, i| lc.mag[i].is_nan() || (lc.mag[i] - median).abs() <= threshold);
+            if iter_rejected == 0 {
+                break;
+            }
+            rejected += iter_rejected;
+        }
+        rejected
+    }
+}
+
+/// Merge consecutive observations of the same time, light curve must be sorted. Magnitude and
+/// flux of merged observation are weighted means and their weights are sums of weights
+fn merge_duplicates(lc: &mut LightCurve) -> usize {
+    let len = lc.t.len();
+    if len == 0 {
+        return 0;
+    }
+    let mut j = 0;
+    let mut w_mag = lc.w[0] * lc.mag[0];
+    let mut w_flux = lc.flux_w[0] * lc.flux[0];
+    for i in 1..len {
+        if lc.t[i] == lc.t[j] {
+            w_mag += lc.w[i] * lc.mag[i];
+            lc.w[j] += lc.w[i];
+            w_flux += lc.flux_w[i] * lc.flux[i];
+            lc.flux_w[j] += lc.flux_w[i];
+        } else {
+            lc.mag[j] = w_mag / lc.w[j];
+            lc.flux[j] = w_flux / lc.flux_w[j];
+            j += 1;
+            lc.move_observation(i, j);
+            w_mag = lc.w[j] * lc.mag[j];
+            w_flux = lc.flux_w[j] * lc.flux[j];
+        }
+    }
+    lc.mag[j] = w_mag / lc.w[j];
+    lc.flux[j] = w_flux / lc.flux_w[j];
+    lc.truncate(j + 1);
+    len - (j + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::MAG_ZP_F32;
+    use crate::lc::mag_to_flux;
+
+    fn light_curve(t: &[f32], mag: &[f32], w: &[f32]) -> LightCurve {
+        let flux: Vec<_> = mag
+            .iter()
+            .map(|&mag| mag_to_flux(mag, MAG_ZP_F32))
+            .collect();
+        let flux_w = flux
+            .iter()
+            .zip(w.iter())
+            .map(|(&flux, &w)| w * flux_to_mag_weight_ratio(flux))
+            .collect();
+        LightCurve {
+            t: t.to_vec(),
+            mag: mag.to_vec(),
+            w: w.to_vec(),
+            flux,
+            flux_w,
+        }
+    }
+
+    #[test]
+    fn quality_filter_drops_and_merges() {
+        let filter = QualityFilter {
+            drop_non_finite: true,
+            magerr_max: Some(1.0),
+            merge_duplicates: true,
+            ..Default::default()
+        };
+        let mut lc = light_curve(
+            &[0.0, 1.0, 1.0, 2.0, 3.0, 4.0],
+            &[10.0, 11.0, 13.0, f32::NAN, 12.0, 12.0],
+            &[1.0, 1.0, 3.0, 1.0, f32::INFINITY, 0.25],
+        );
+        let counts = filter.apply_lc(&mut lc);
+        assert_eq!(
+            counts,
+            RejectionCounts {
+                non_finite: 2,
+                large_magerr: 1,
+                duplicate: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(lc.t, [0.0, 1.0]);
+        assert_eq!(lc.mag, [10.0, 12.5]);
+        assert_eq!(lc.w, [1.0, 4.0]);
+    }
+
+    #[test]
+    fn magerr_floor_applies_to_negative_flux() {
+        let filter = QualityFilter {
+            magerr_floor: Some(0.5),
+            ..Default::default()
+        };
+        let mut lc = light_curve(&[0.0, 1.0], &[15.0, 15.0], &[100.0, 1.0]);
+        // Negative native flux has undefined magnitude
+        lc.mag.push(f32::NAN);
+        lc.w.push(f32::NAN);
+        lc.t.push(2.0);
+        lc.flux.push(-lc.flux[0]);
+        lc.flux_w.push(lc.flux_w[0]);
+        filter.apply_lc(&mut lc);
+        assert_eq!(lc.w[..2], [4.0, 1.0]);
+        assert!(lc.w[2].is_nan());
+        let flux_w_max = 4.0 * flux_to_mag_weight_ratio(lc.flux[0]);
+        assert_eq!(lc.flux_w, [flux_w_max, lc.flux_w[1], flux_w_max]);
+    }
+
+    #[test]
+    fn source_selection_time_window_and_min_obs() {
+        let selection = SourceSelection {
+            mjd_min: Some(MJD0 + 1.0),
+            mjd_max: Some(MJD0 + 3.0),
+            min_obs: Some(3),
+        };
+        let passbands = crate::lc::PassbandRegistry::default().parse_names("gr");
+        let mut source = Source::new(0);
+        *source.lc_mut(passbands[0].code) =
+            light_curve(&[0.0, 1.0, 2.0, 3.0], &[0.0; 4], &[1.0; 4]);
+        *source.lc_mut(passbands[1].code) = light_curve(&[1.0, 2.0, 4.0], &[0.0; 3], &[1.0; 3]);
+        assert_eq!(selection.apply_time_window(&mut source), 2);
+        assert_eq!(source.lc(&passbands[0]).t, [1.0, 2.0, 3.0]);
+        assert!(selection.check_min_obs(&source, &passbands[..1]).is_ok());
+        assert!(selection.check_min_obs(&source, &passbands).is_err());
+    }
+
+    #[test]
+    fn sigma_clip_rejects_outlier() {
+        let sigma_clip = SigmaClip {
+            nsigma: 2.0,
+            max_iter: 5,
+        };
+        let mut mag = vec![15.0, 15.1, 14.9, 15.0, 15.1, 14.9, 15.0, 15.1, 14.9, 20.0];
+        let t: Vec<_> = (0..mag.len()).map(|i| i as f32).collect();
+        let w = vec![1.0; mag.len()];
+        let mut lc = light_curve(&t, &mag, &w);
+        assert_eq!(sigma_clip.apply(&mut lc), 1);
+        mag.pop();
+        assert_eq!(lc.mag, mag);
+    }
+}
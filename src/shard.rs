@@ -0,0 +1,176 @@
+use crate::ch::subquery;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Part of sources to process, sources are partitioned by a hash of their sid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    /// Wrap ClickHouse query to select shard sources only, query must have sid column
+    ///
+    /// Unsorted sources are ordered by sid, otherwise observations of a source could be
+    /// interleaved with other ones. Sorted light curves keep the order of the query, because
+    /// ordering by sid could shuffle observations of a source
+    pub fn wrap_query(&self, query: &str, sorted: bool) -> Result<String, String> {
+        Ok(format!(
+            "SELECT * FROM ({}) WHERE cityHash64(sid) % {} = {}{}",
+            subquery(query)?,
+            self.count,
+            self.index,
+            if sorted { "" } else { " ORDER BY sid" }
+        ))
+    }
+
+    /// Client-side check if source belongs to the shard, it gives the same partition as
+    /// `wrap_query` for integer sid columns
+    pub fn contains(&self, sid: u64) -> bool {
+        Self::hash(sid) % self.count == self.index
+    }
+
+    /// Output filename suffix of the shard
+    pub fn suffix(&self) -> String {
+        format!("_shard{}of{}", self.index, self.count)
+    }
+
+    /// ClickHouse cityHash64 of an integer, which is intHash64 of its value. It is a MurmurHash3
+    /// finalizer, so sequential sids are spread over shards uniformly
+    fn hash(sid: u64) -> u64 {
+        let mut x = sid;
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xff51afd7ed558ccd);
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+        x ^ (x >> 33)
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    /// Parse shard from a string like "0/4"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("shard {} must be specified as i/N", s))?;
+        let index = index
+            .trim()
+            .parse()
+            .map_err(|_| format!("shard index {} is invalid", index))?;
+        let count = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("shard count {} is invalid", count))?;
+        if index >= count {
+            return Err(format!("shard index must be less than count, not {}", s));
+        }
+        Ok(Self { index, count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_from_str() {
+        assert_eq!(
+            "1/4".parse::<Shard>().unwrap(),
+            Shard { index: 1, count: 4 }
+        );
+        assert!("4/4".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+        assert!("-1/4".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn shards_partition_sids() {
+        let count = 3;
+        let shards: Vec<Shard> = (0..count).map(|index| Shard { index, count }).collect();
+        for sid in 0..1000 {
+            let n = shards.iter().filter(|shard| shard.contains(sid)).count();
+            assert_eq!(n, 1);
+        }
+        for shard in shards.iter() {
+            let n = (0..1000).filter(|&sid| shard.contains(sid)).count();
+            assert!(n > 250, "shard {} is too small: {}", shard, n);
+        }
+    }
+
+    #[test]
+    fn wrap_query_sql() {
+        let shard = Shard { index: 1, count: 4 };
+        let expected = "SELECT * FROM (SELECT sid, mjd FROM t) \
+            WHERE cityHash64(sid) % 4 = 1 ORDER BY sid";
+        assert_eq!(
+            shard.wrap_query("SELECT sid, mjd FROM t", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            shard
+                .wrap_query("SELECT sid, mjd FROM t ;\n", false)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            shard
+                .wrap_query("SELECT sid, mjd FROM t -- don't format; \n", false)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            shard
+                .wrap_query("SELECT sid, mjd FROM t; /* settings */", false)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            shard
+                .wrap_query(
+                    "SELECT sid, mjd FROM (SELECT * FROM t SETTINGS max_threads = 1)",
+                    false
+                )
+                .unwrap(),
+            "SELECT * FROM (SELECT sid, mjd FROM (SELECT * FROM t SETTINGS max_threads = 1)) \
+            WHERE cityHash64(sid) % 4 = 1 ORDER BY sid"
+        );
+        assert_eq!(
+            shard
+                .wrap_query("SELECT sid, mjd FROM t ORDER BY sid, mjd", true)
+                .unwrap(),
+            "SELECT * FROM (SELECT sid, mjd FROM t ORDER BY sid, mjd) \
+            WHERE cityHash64(sid) % 4 = 1"
+        );
+        assert!(shard
+            .wrap_query("SELECT sid, 'a\\'b; format' AS mjd FROM t", false)
+            .is_ok());
+        assert!(shard
+            .wrap_query("SELECT sid, mjd FROM t /* ; */ FORMAT Native", false)
+            .is_err());
+        assert!(shard
+            .wrap_query("SELECT sid, mjd FROM t; -- comment\nSELECT 1", false)
+            .is_err());
+        assert!(shard
+            .wrap_query("SELECT sid, 'format' AS mjd FROM t", false)
+            .is_ok());
+        assert!(shard
+            .wrap_query("SELECT sid, mjd FROM t FORMAT Native", false)
+            .is_err());
+        assert!(shard
+            .wrap_query("SELECT sid, mjd FROM t SETTINGS max_threads = 1;", false)
+            .is_err());
+        assert!(shard
+            .wrap_query("SELECT 1; SELECT sid, mjd FROM t", false)
+            .is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::lc::{LightCurve, Observation, Source};
+use crate::lc::{Observation, Source};
 use dyn_clonable::*;
 
 pub trait SourceDataBase<'a> {
@@ -7,12 +7,35 @@ pub trait SourceDataBase<'a> {
     fn query(&'a mut self, query: &str) -> Self::Query;
 }
 
+/// Dump evaluation result, values which cannot be evaluated are filled with NaN
+pub struct EvalResult {
+    pub data: Vec<u8>,
+    pub errors: Vec<String>,
+}
+
+impl From<Vec<u8>> for EvalResult {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            errors: vec![],
+        }
+    }
+}
+
 #[clonable]
 pub trait Dump: Clone + Send {
-    fn eval(&self, source: &Source) -> Vec<u8>;
+    fn eval(&self, source: &Source) -> EvalResult;
     fn get_names(&self) -> Vec<&str>;
     fn get_json(&self) -> &str;
     fn get_value_path(&self) -> &str;
+    /// Short name of values, like "flux", it is used for table columns and datasets
+    fn get_value_name(&self) -> &str;
+    /// NumPy type string of values without byte order, like "f4"
+    fn get_value_dtype(&self) -> &str;
+    /// Shape of values of a single source, it is empty for a scalar
+    fn get_value_shape(&self) -> Vec<usize>;
+    /// MJD grid of values, like the interpolation grid of fluxes
+    fn get_value_grid(&self) -> Option<&[f64]>;
     fn get_name_path(&self) -> Option<&str>;
     fn get_json_path(&self) -> Option<&str>;
 }
@@ -65,26 +88,7 @@ where
     type Item = Source;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut source = Source {
-            sid: 0,
-            lcs: [
-                LightCurve {
-                    t: vec![],
-                    mag: vec![],
-                    w: vec![],
-                },
-                LightCurve {
-                    t: vec![],
-                    mag: vec![],
-                    w: vec![],
-                },
-                LightCurve {
-                    t: vec![],
-                    mag: vec![],
-                    w: vec![],
-                },
-            ],
-        };
+        let mut source = Source::new(0);
 
         source.sid = match self.current_obs.as_ref() {
             Some(obs) => {
@@ -117,3 +121,46 @@ where
         Some(source)
     }
 }
+
+/// Dump with fixed names and value shape for tests of the dumper and writers, its evaluation
+/// panics
+#[cfg(test)]
+#[derive(Clone)]
+pub struct PanicDump {
+    pub names: Vec<&'static str>,
+    pub shape: Vec<usize>,
+}
+
+#[cfg(test)]
+impl Dump for PanicDump {
+    fn eval(&self, source: &Source) -> EvalResult {
+        panic!("Cannot evaluate source {}", source.sid)
+    }
+    fn get_names(&self) -> Vec<&str> {
+        self.names.clone()
+    }
+    fn get_json(&self) -> &str {
+        "{}"
+    }
+    fn get_value_path(&self) -> &str {
+        ""
+    }
+    fn get_value_name(&self) -> &str {
+        "features"
+    }
+    fn get_value_dtype(&self) -> &str {
+        "f4"
+    }
+    fn get_value_shape(&self) -> Vec<usize> {
+        self.shape.clone()
+    }
+    fn get_value_grid(&self) -> Option<&[f64]> {
+        None
+    }
+    fn get_name_path(&self) -> Option<&str> {
+        None
+    }
+    fn get_json_path(&self) -> Option<&str> {
+        None
+    }
+}